@@ -1,26 +1,16 @@
 use icap_poc::{
-    server::{AdaptationDecision::*, ReqCtx, ServerCfg, TcpAcceptor},
+    server::{AdaptationDecision::*, OptionsResponse, ReqCtx, ServerCfg, TcpAcceptor},
     service::ServiceResult,
     service_fn,
 };
 
 use http::StatusCode;
-use std::{boxed::Box, io::Result};
+use std::{boxed::Box, io::Result, time::Duration};
 use tracing::instrument;
 
-const DEFAULT_IS_TAG: &str = env!("DEFAULT_IS_TAG");
-
 #[instrument(err)]
 async fn handle_options(mut ctx: Box<ReqCtx>) -> ServiceResult {
     ctx.set_icap_status(StatusCode::OK);
-    ctx.append_icap_res_header("Server", "r-bk/icap");
-    ctx.append_icap_res_header("Service", "r-bk/icap server example");
-    ctx.append_icap_res_header("ISTag", DEFAULT_IS_TAG);
-    ctx.append_icap_res_header("Allow", "204, 206");
-    ctx.append_icap_res_header("Methods", "REQMOD, RESPMOD");
-    ctx.append_icap_res_header("Preview", "0");
-    ctx.append_icap_res_header("Transfer-Preview", "*");
-    ctx.append_icap_res_header("Connection", "keep-alive");
     Ok(ctx)
 }
 
@@ -44,7 +34,12 @@ async fn handle_respmod(mut ctx: Box<ReqCtx>) -> ServiceResult {
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
-    let cfg = ServerCfg::builder().build();
+    let options = OptionsResponse::builder()
+        .service("r-bk/icap server example")
+        .options_ttl(Duration::from_secs(3600))
+        .build()
+        .unwrap();
+    let cfg = ServerCfg::builder().options(options).build();
 
     let svc = service_fn(cfg, handle_options, handle_reqmod, handle_respmod);
 
@@ -4,8 +4,9 @@ use crate::{
     header::{HeaderIndices, HeaderIndicesList},
     Method, Version,
 };
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use http::StatusCode;
-use std::{mem::MaybeUninit, str::FromStr};
+use std::{net::IpAddr, str::FromStr};
 use tracing::{error, instrument, trace};
 
 mod encapsulated;
@@ -17,7 +18,8 @@ mod macros;
 mod maps;
 use maps::*;
 
-const MAX_HEADERS: usize = 128;
+/// Arrays of up to this many headers are allocated on the stack while decoding
+const STACK_HEADERS: usize = 128;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum DecodingStatus {
@@ -40,15 +42,17 @@ struct RawRequestParts<'b> {
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
-pub struct Allow {
+pub(crate) struct Allow {
     pub allow_204: bool,
     pub allow_206: bool,
+    pub allow_trailers: bool,
 }
 
 impl Allow {
     pub fn add(&mut self, other: &Allow) {
         self.allow_204 = self.allow_204 || other.allow_204;
         self.allow_206 = self.allow_206 || other.allow_206;
+        self.allow_trailers = self.allow_trailers || other.allow_trailers;
     }
 }
 
@@ -79,8 +83,9 @@ pub(crate) fn decode_http_request(
     bytes: &[u8],
     base_ptr: usize,
     req: &mut HttpRequest,
+    max_headers: usize,
 ) -> Result<Option<usize>, DecoderError> {
-    match decode_raw_request_parts(bytes, base_ptr, &mut req.headers) {
+    match decode_raw_request_parts(bytes, base_ptr, &mut req.headers, max_headers) {
         Ok(Some((parsed_len, parts))) => {
             let version = match parts.version {
                 0 => http::Version::HTTP_10,
@@ -112,8 +117,9 @@ pub(crate) fn decode_icap_request(
     bytes: &[u8],
     base_ptr: usize,
     req: &mut IcapRequest,
+    max_headers: usize,
 ) -> Result<Option<usize>, DecoderError> {
-    match decode_raw_request_parts(bytes, base_ptr, &mut req.headers) {
+    match decode_raw_request_parts(bytes, base_ptr, &mut req.headers, max_headers) {
         Ok(Some((parsed_len, parts))) => {
             if parts.version != 10 {
                 return Err(DecoderError::BadVersion(format!(
@@ -138,10 +144,19 @@ pub(crate) fn decode_http_response(
     bytes: &[u8],
     base_ptr: usize,
     resp: &mut HttpResponse,
+    max_headers: usize,
 ) -> Result<usize, DecoderError> {
-    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut stack_headers;
+    let mut heap_headers;
+    let headers: &mut [httparse::Header<'_>] = if max_headers <= STACK_HEADERS {
+        stack_headers = [httparse::EMPTY_HEADER; STACK_HEADERS];
+        &mut stack_headers[..max_headers]
+    } else {
+        heap_headers = vec![httparse::EMPTY_HEADER; max_headers];
+        &mut heap_headers
+    };
 
-    let mut res = httparse::Response::new(&mut headers);
+    let mut res = httparse::Response::new(headers);
 
     let parsed_len = match res.parse(bytes) {
         Ok(httparse::Status::Complete(parsed_len)) => parsed_len,
@@ -196,58 +211,115 @@ pub(crate) fn decode_http_response(
     Ok(parsed_len)
 }
 
+/// Request line versions, as numbered by httparse for HTTP
+const REQUEST_VERSIONS: [(&[u8; 8], u8); 3] =
+    [(b"HTTP/1.0", 0), (b"HTTP/1.1", 1), (b"ICAP/1.0", 10)];
+
+/// Decodes `method SP request-target SP version CRLF`, leading empty lines are skipped
+///
+/// Returns the length of the line with the method, the target and the version,
+/// or `None` while the line is incomplete.
+fn decode_request_line(bytes: &[u8]) -> Result<Option<(usize, &str, &str, u8)>, DecoderError> {
+    let mut pos = 0;
+    loop {
+        match &bytes[pos..] {
+            [b'\r', b'\n', ..] => pos += 2,
+            [b'\n', ..] => pos += 1,
+            [] | [b'\r'] => return Ok(None),
+            _ => break,
+        }
+    }
+
+    let method_start = pos;
+    while pos < bytes.len() && is_token(bytes[pos]) {
+        pos += 1;
+    }
+    match bytes.get(pos) {
+        None => return Ok(None),
+        Some(b' ') if pos > method_start => (),
+        Some(_) => return Err(DecoderError::BadMethod("invalid method token".into())),
+    }
+    // SAFETY: token characters are ASCII
+    let method = unsafe { std::str::from_utf8_unchecked(&bytes[method_start..pos]) };
+    pos += 1;
+
+    let uri_start = pos;
+    while pos < bytes.len() && bytes[pos].is_ascii_graphic() {
+        pos += 1;
+    }
+    match bytes.get(pos) {
+        None => return Ok(None),
+        Some(b' ') if pos > uri_start => (),
+        Some(_) => return Err(DecoderError::BadUri("invalid URI token".into())),
+    }
+    // SAFETY: visible characters are ASCII
+    let uri = unsafe { std::str::from_utf8_unchecked(&bytes[uri_start..pos]) };
+    pos += 1;
+
+    let avail = &bytes[pos..bytes.len().min(pos + 8)];
+    let version = match REQUEST_VERSIONS.iter().find(|(v, _)| v.starts_with(avail)) {
+        Some((v, n)) if avail.len() == v.len() => *n,
+        Some(_) => return Ok(None),
+        None => return Err(DecoderError::BadFormat("invalid version".into())),
+    };
+    pos += 8;
+
+    match &bytes[pos..] {
+        [b'\r', b'\n', ..] => pos += 2,
+        [b'\n', ..] => pos += 1,
+        [] | [b'\r'] => return Ok(None),
+        _ => return Err(DecoderError::BadFormat("invalid new line".into())),
+    }
+    Ok(Some((pos, method, uri, version)))
+}
+
 fn decode_raw_request_parts<'b>(
     bytes: &'b [u8],
     base_ptr: usize,
     indices: &mut HeaderIndicesList,
+    max_headers: usize,
 ) -> Result<Option<(usize, RawRequestParts<'b>)>, DecoderError> {
-    let mut headers: [MaybeUninit<httparse::Header<'_>>; MAX_HEADERS] =
-        unsafe { MaybeUninit::uninit().assume_init() };
+    let mut stack_headers;
+    let mut heap_headers;
+    let headers: &mut [httparse::Header<'_>] = if max_headers <= STACK_HEADERS {
+        stack_headers = [httparse::EMPTY_HEADER; STACK_HEADERS];
+        &mut stack_headers[..max_headers]
+    } else {
+        heap_headers = vec![httparse::EMPTY_HEADER; max_headers];
+        &mut heap_headers
+    };
 
     trace!(bytes = bytes.len(), "start");
 
-    let mut req = httparse::Request::new(&mut []);
+    let (line_len, method, path, version) = match decode_request_line(bytes)? {
+        Some(line) => line,
+        None => {
+            trace!("partial");
+            return Ok(None);
+        }
+    };
 
-    let parsed_len = match req.parse_with_uninit_headers(bytes, &mut headers) {
-        Ok(httparse::Status::Complete(parsed_len)) => {
-            trace!("complete({})", parsed_len);
-            parsed_len
+    let (parsed_len, headers) = match httparse::parse_headers(&bytes[line_len..], headers) {
+        Ok(httparse::Status::Complete((len, headers))) => {
+            trace!("complete({})", line_len + len);
+            (line_len + len, headers)
         }
         Ok(httparse::Status::Partial) => {
             trace!("partial");
             return Ok(None);
         }
-        Err(err) => {
-            return Err(match err {
-                // if invalid Token, try to determine if for method or path
-                httparse::Error::Token => {
-                    if req.method.is_none() {
-                        DecoderError::BadMethod("invalid method token".into())
-                    } else {
-                        debug_assert!(req.path.is_none());
-                        DecoderError::BadUri("invalid URI token".into())
-                    }
-                }
-                other => DecoderError::BadFormat(other.to_string()),
-            });
-        }
+        Err(e) => return Err(DecoderError::BadFormat(e.to_string())),
     };
 
-    let method = req.method.unwrap();
-    let version = req.version.unwrap();
-
-    let uri = match http::Uri::from_str(req.path.unwrap()) {
+    let uri = match http::Uri::from_str(path) {
         Ok(v) => v,
         Err(e) => return Err(DecoderError::BadUri(e.to_string())),
     };
 
-    let n_headers = req.headers.len();
     indices.clear();
     indices.base_ptr = base_ptr;
 
-    // SAFETY: first n_headers are init
-    for header in &headers[..n_headers] {
-        let hdr = unsafe { header.assume_init_ref() };
+    for hdr in headers.iter() {
         let name_start = hdr.name.as_ptr() as usize - base_ptr;
         let name_end = name_start + hdr.name.len();
         let value_start = hdr.value.as_ptr() as usize - base_ptr;
@@ -271,11 +343,13 @@ fn decode_raw_request_parts<'b>(
 pub(crate) fn decode_allow(bytes: &[u8]) -> Result<Allow, DecoderError> {
     let mut allow_204 = false;
     let mut allow_206 = false;
+    let mut allow_trailers = false;
 
     for slc in bytes.split(|b| b.is_ascii_whitespace() || *b == b',') {
         match slc {
             b"204" => allow_204 = true,
             b"206" => allow_206 = true,
+            b"trailers" => allow_trailers = true,
             _ => continue,
         }
     }
@@ -283,9 +357,56 @@ pub(crate) fn decode_allow(bytes: &[u8]) -> Result<Allow, DecoderError> {
     Ok(Allow {
         allow_204,
         allow_206,
+        allow_trailers,
     })
 }
 
+/// Decodes a trailer section, i.e. header fields terminated by an empty line
+///
+/// Header indices are relative to the start of `bytes`.
+/// Returns the length of the section once it is complete.
+pub(crate) fn decode_trailer(
+    bytes: &[u8],
+    indices: &mut HeaderIndicesList,
+    max_headers: usize,
+) -> Result<Option<usize>, DecoderError> {
+    let mut stack_headers;
+    let mut heap_headers;
+    let headers: &mut [httparse::Header<'_>] = if max_headers <= STACK_HEADERS {
+        stack_headers = [httparse::EMPTY_HEADER; STACK_HEADERS];
+        &mut stack_headers[..max_headers]
+    } else {
+        heap_headers = vec![httparse::EMPTY_HEADER; max_headers];
+        &mut heap_headers
+    };
+
+    let (parsed_len, headers) = match httparse::parse_headers(bytes, headers) {
+        Ok(httparse::Status::Complete(res)) => res,
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(e) => {
+            error!(err = %e, "failed to parse trailer");
+            return Err(DecoderError::BadFormat(e.to_string()));
+        }
+    };
+
+    let base_ptr = bytes.as_ptr() as usize;
+    indices.clear();
+    indices.base_ptr = base_ptr;
+
+    for hdr in headers {
+        let name_start = hdr.name.as_ptr() as usize - base_ptr;
+        let name_end = name_start + hdr.name.len();
+        let value_start = hdr.value.as_ptr() as usize - base_ptr;
+        let value_end = value_start + hdr.value.len();
+        indices.vec.push(HeaderIndices {
+            name: (name_start, name_end),
+            value: (value_start, value_end),
+        });
+    }
+
+    Ok(Some(parsed_len))
+}
+
 pub(crate) fn decode_preview(bytes: &[u8]) -> Result<usize, DecoderError> {
     for slc in bytes.split(|b| b.is_ascii_whitespace()) {
         if !slc.is_empty() && slc.is_ascii() {
@@ -352,6 +473,34 @@ impl Default for ExtensionIndices {
     }
 }
 
+/// Decodes the value of header `name` as a UTF-8 string with surrounding whitespace trimmed
+pub(crate) fn decode_str<'b>(bytes: &'b [u8], name: &'static str) -> Result<&'b str, DecoderError> {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => Ok(s.trim()),
+        Err(_) => {
+            error!(val = ?bytes, "non UTF-8 '{}' value", name);
+            Err(DecoderError::BadHeaderValue(name))
+        }
+    }
+}
+
+/// Decodes the value of header `name` as an IPv4 or IPv6 address
+pub(crate) fn decode_ip_addr(bytes: &[u8], name: &'static str) -> Result<IpAddr, DecoderError> {
+    decode_str(bytes, name)?.parse().map_err(|_| {
+        error!(val = ?bytes, "failed to parse '{}' address", name);
+        DecoderError::BadHeaderValue(name)
+    })
+}
+
+/// Decodes the value of header `name` as a base64 encoded UTF-8 string
+pub(crate) fn decode_base64(bytes: &[u8], name: &'static str) -> Result<String, DecoderError> {
+    let decoded = BASE64.decode(bytes.trim_ascii()).map_err(|e| {
+        error!(err = %e, "failed to decode base64 '{}' value", name);
+        DecoderError::BadBase64(name)
+    })?;
+    String::from_utf8(decoded).map_err(|_| DecoderError::BadHeaderValue(name))
+}
+
 #[instrument(skip(bytes))]
 pub fn decode_chunk_header(bytes: &[u8]) -> Result<Option<ChunkHdr>, DecoderError> {
     use ChunkHeaderState::*;
@@ -579,6 +728,34 @@ mod tests {
     use super::*;
     use tracing_test::traced_test;
 
+    #[test]
+    fn test_decode_header_values() {
+        assert_eq!(
+            decode_ip_addr(b" 192.168.1.1", "X-Client-IP"),
+            Ok(IpAddr::from([192, 168, 1, 1]))
+        );
+        assert_eq!(
+            decode_ip_addr(b"::1", "X-Client-IP"),
+            Ok(IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]))
+        );
+        assert_eq!(
+            decode_ip_addr(b"192.168.1", "X-Server-IP"),
+            Err(DecoderError::BadHeaderValue("X-Server-IP"))
+        );
+        assert_eq!(
+            decode_base64(b"TG9jYWw6Ly9qb2U=", "X-Authenticated-User").as_deref(),
+            Ok("Local://joe")
+        );
+        assert_eq!(
+            decode_base64(b"Local://joe", "X-Authenticated-User"),
+            Err(DecoderError::BadBase64("X-Authenticated-User"))
+        );
+        assert_eq!(
+            decode_str(b"\xff", "X-Subscriber-ID"),
+            Err(DecoderError::BadHeaderValue("X-Subscriber-ID"))
+        );
+    }
+
     #[test]
     fn test_decode_allow() {
         let expectations: Vec<(&[u8], Allow)> = vec![
@@ -587,6 +764,7 @@ mod tests {
                 Allow {
                     allow_204: true,
                     allow_206: false,
+                    allow_trailers: false,
                 },
             ),
             (
@@ -594,6 +772,7 @@ mod tests {
                 Allow {
                     allow_204: false,
                     allow_206: true,
+                    allow_trailers: false,
                 },
             ),
             (
@@ -601,6 +780,7 @@ mod tests {
                 Allow {
                     allow_204: true,
                     allow_206: true,
+                    allow_trailers: false,
                 },
             ),
             (
@@ -608,6 +788,7 @@ mod tests {
                 Allow {
                     allow_204: false,
                     allow_206: false,
+                    allow_trailers: true,
                 },
             ),
             (
@@ -615,6 +796,7 @@ mod tests {
                 Allow {
                     allow_204: true,
                     allow_206: true,
+                    allow_trailers: true,
                 },
             ),
             (
@@ -622,6 +804,7 @@ mod tests {
                 Allow {
                     allow_204: true,
                     allow_206: false,
+                    allow_trailers: true,
                 },
             ),
             (
@@ -629,6 +812,7 @@ mod tests {
                 Allow {
                     allow_204: false,
                     allow_206: false,
+                    allow_trailers: true,
                 },
             ),
             (
@@ -636,6 +820,7 @@ mod tests {
                 Allow {
                     allow_204: false,
                     allow_206: false,
+                    allow_trailers: false,
                 },
             ),
         ];
@@ -737,6 +922,83 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_decode_max_headers() {
+        let mut buf = Vec::new();
+        for i in 0..200 {
+            buf.extend(format!("X-{}: {}\r\n", i, i).as_bytes());
+        }
+        buf.extend(b"\r\n");
+
+        let mut indices = HeaderIndicesList::default();
+        assert_eq!(decode_trailer(&buf, &mut indices, 256), Ok(Some(buf.len())));
+        assert_eq!(indices.vec.len(), 200);
+        assert!(decode_trailer(&buf, &mut indices, 199).is_err());
+        assert!(decode_trailer(&buf, &mut indices, STACK_HEADERS).is_err());
+    }
+
+    #[test]
+    fn test_decode_trailer() {
+        let mut indices = HeaderIndicesList::default();
+
+        assert_eq!(
+            decode_trailer(b"\r\n", &mut indices, STACK_HEADERS),
+            Ok(Some(2))
+        );
+        assert!(indices.vec.is_empty());
+
+        assert_eq!(
+            decode_trailer(b"X-A: 1\r\n", &mut indices, STACK_HEADERS),
+            Ok(None)
+        );
+
+        let buf = b"X-A: 1\r\nX-B: two\r\n\r\nnext";
+        assert_eq!(
+            decode_trailer(buf, &mut indices, STACK_HEADERS),
+            Ok(Some(buf.len() - 4))
+        );
+        assert_eq!(indices.vec.len(), 2);
+        let hi = &indices.vec[1];
+        assert_eq!(&buf[hi.name.0..hi.name.1], b"X-B");
+        assert_eq!(&buf[hi.value.0..hi.value.1], b"two");
+
+        assert!(decode_trailer(b"X A\r\n\r\n", &mut indices, STACK_HEADERS).is_err());
+    }
+
+    #[test]
+    fn test_decode_request_line() {
+        let buf = b"\r\nREQMOD icap://localhost/svc ICAP/1.0\r\nHost";
+        assert_eq!(
+            decode_request_line(buf),
+            Ok(Some((buf.len() - 4, "REQMOD", "icap://localhost/svc", 10)))
+        );
+        assert_eq!(
+            decode_request_line(b"GET / HTTP/1.1\n"),
+            Ok(Some((15, "GET", "/", 1)))
+        );
+
+        for partial in [&b"GET"[..], b"GET /", b"GET / HT", b"GET / ICAP/1.0\r"] {
+            assert_eq!(decode_request_line(partial), Ok(None));
+        }
+
+        assert!(matches!(
+            decode_request_line(b"GE(T / HTTP/1.1\r\n"),
+            Err(DecoderError::BadMethod(_))
+        ));
+        assert!(matches!(
+            decode_request_line(b"GET /\x01 HTTP/1.1\r\n"),
+            Err(DecoderError::BadUri(_))
+        ));
+        assert!(matches!(
+            decode_request_line(b"GET / HTTP/2.0\r\n"),
+            Err(DecoderError::BadFormat(_))
+        ));
+        assert!(matches!(
+            decode_request_line(b"GET / HTTP/1.1 \r\n"),
+            Err(DecoderError::BadFormat(_))
+        ));
+    }
+
     // #[test]
     // fn test_decode_icap_request() {
     //     let buf = b"OPTIONS icap://my.icap.server/path?key=val ICAP/1.0\r\n\
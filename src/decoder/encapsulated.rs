@@ -93,7 +93,7 @@ impl EncapsulatedEntity {
 
 #[derive(Debug, Clone, Default)]
 #[repr(transparent)]
-pub struct EeList(Vec<EncapsulatedEntity>);
+pub(crate) struct EeList(Vec<EncapsulatedEntity>);
 
 impl EeList {
     #[inline]
@@ -106,6 +106,11 @@ impl EeList {
         self.0.iter()
     }
 
+    #[inline]
+    pub fn as_slice(&self) -> &[EncapsulatedEntity] {
+        &self.0
+    }
+
     #[inline]
     pub fn clear(&mut self) {
         self.0.clear()
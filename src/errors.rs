@@ -1,3 +1,5 @@
+#[cfg(feature = "tls")]
+use std::path::PathBuf;
 use std::{fmt, io};
 use thiserror::Error;
 
@@ -11,6 +13,35 @@ pub struct BadIcapMethodError;
 #[non_exhaustive]
 pub struct BadIcapVersionError;
 
+#[derive(Error, Debug, Copy, Clone, PartialEq, Eq)]
+#[error("bad header value")]
+#[non_exhaustive]
+pub struct HeaderValueError;
+
+#[derive(Error, Debug, Copy, Clone)]
+#[error("body sink closed")]
+#[non_exhaustive]
+pub struct BodySinkClosedError;
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum OptionsError {
+    #[error("no methods")]
+    NoMethods,
+    #[error("OPTIONS in 'Methods'")]
+    BadMethod,
+    #[error("bad ISTag: {0}")]
+    BadIsTag(String),
+    #[error("bad '{0}' value")]
+    BadValue(&'static str),
+    #[error("zero '{0}' value")]
+    ZeroValue(&'static str),
+    #[error("bad file extension in '{0}'")]
+    BadTransferList(&'static str),
+    #[error("'*' in more than one transfer list")]
+    MultipleWildcards,
+}
+
 #[derive(Debug, Error, PartialEq, Eq)]
 #[non_exhaustive]
 pub enum DecoderError {
@@ -34,14 +65,48 @@ pub enum DecoderError {
     FailedToParseHttpRes,
     #[error("failed to parse 'Preview' header")]
     FailedToParsePreview,
-    #[error("206 response not allowed")]
-    NoAllow206,
-    #[error("no 'Preview: 0' found")]
-    NoPreview0,
+    #[error("'Trailer' header without 'Allow: trailers'")]
+    TrailerNotAllowed,
     #[error("bad chunk header")]
     BadChunkHeader,
     #[error("failed to parse chunk size")]
     BadChunkSize,
+    #[error("missing CRLF after chunk data")]
+    BadChunkData,
+    #[error("bad '{0}' header value")]
+    BadHeaderValue(&'static str),
+    #[error("bad base64 in '{0}' header")]
+    BadBase64(&'static str),
+}
+
+#[cfg(feature = "config")]
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum FileCfgError {
+    #[error("failed to read config file: {0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Parse(String),
+    #[error("line {line}: {msg}")]
+    Invalid { line: usize, msg: String },
+}
+
+#[cfg(feature = "tls")]
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum TlsCfgError {
+    #[error("failed to read '{path}': {source}")]
+    Io { path: PathBuf, source: io::Error },
+    #[error("no certificates in '{0}'")]
+    NoCertificates(PathBuf),
+    #[error("no private key in '{0}'")]
+    NoPrivateKey(PathBuf),
+    #[error("no {0} given")]
+    Missing(&'static str),
+    #[error("bad client CA certificate: {0}")]
+    BadClientCa(String),
+    #[error("bad TLS configuration: {0}")]
+    Rustls(#[from] tokio_rustls::rustls::Error),
 }
 
 #[derive(Debug, Error)]
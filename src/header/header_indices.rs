@@ -19,15 +19,75 @@ impl HeaderIndicesList {
         self.base_ptr = 0;
     }
 
-    #[inline]
-    pub fn encode(&self, data_buf: &BytesMut, wbuf: &mut BytesMut) {
+    /// Encodes all headers except those with a name in `skip` (case-insensitive)
+    ///
+    /// Headers named in `replace` are encoded at the position of their first occurrence
+    /// with the values from `replace`, the ones not found are encoded last.
+    pub fn encode_adapted(
+        &self,
+        data_buf: &BytesMut,
+        wbuf: &mut BytesMut,
+        skip: &[&str],
+        replace: &http::HeaderMap,
+    ) {
+        let is_skipped = |name: &[u8]| skip.iter().any(|s| s.as_bytes().eq_ignore_ascii_case(name));
+        let mut replaced = Vec::new();
+
         for hi in self.vec.iter() {
             let name = &data_buf[hi.name.0..hi.name.1];
-            let value = &data_buf[hi.value.0..hi.value.1];
-            wbuf.extend_from_slice(name);
-            wbuf.extend_from_slice(b": ");
-            wbuf.extend_from_slice(value);
-            wbuf.extend_from_slice(b"\r\n");
+            if is_skipped(name) {
+                continue;
+            }
+            match replace
+                .keys()
+                .find(|k| k.as_str().as_bytes().eq_ignore_ascii_case(name))
+            {
+                Some(k) if replaced.contains(&k) => (),
+                Some(k) => {
+                    for value in replace.get_all(k) {
+                        encode_header(wbuf, name, value.as_bytes());
+                    }
+                    replaced.push(k);
+                }
+                None => encode_header(wbuf, name, &data_buf[hi.value.0..hi.value.1]),
+            }
+        }
+
+        for (k, v) in replace.iter() {
+            if !replaced.contains(&k) && !is_skipped(k.as_str().as_bytes()) {
+                encode_header(wbuf, k.as_str().as_bytes(), v.as_bytes());
+            }
         }
     }
 }
+
+#[inline]
+fn encode_header(wbuf: &mut BytesMut, name: &[u8], value: &[u8]) {
+    wbuf.extend_from_slice(name);
+    wbuf.extend_from_slice(b": ");
+    wbuf.extend_from_slice(value);
+    wbuf.extend_from_slice(b"\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::decode_trailer;
+    use http::HeaderValue;
+
+    #[test]
+    fn test_encode_adapted() {
+        let data = BytesMut::from(&b"A: 1\r\nCookie: x\r\nUser-Agent: old\r\nB: 2\r\n\r\n"[..]);
+        let mut indices = HeaderIndicesList::default();
+        decode_trailer(&data, &mut indices, 128).unwrap();
+
+        let mut replace = http::HeaderMap::new();
+        replace.insert("user-agent", HeaderValue::from_static("new"));
+        replace.insert("c", HeaderValue::from_static("3"));
+        replace.insert("content-length", HeaderValue::from_static("0"));
+
+        let mut wbuf = BytesMut::new();
+        indices.encode_adapted(&data, &mut wbuf, &["cookie", "Content-Length"], &replace);
+        assert_eq!(&wbuf[..], b"A: 1\r\nUser-Agent: new\r\nB: 2\r\nc: 3\r\n");
+    }
+}
@@ -1,12 +1,47 @@
 use crate::header::{Header, HeaderIndices, HeaderName, HeaderValue};
 use std::{slice::Iter, str};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct HeaderIterator<'b> {
     pub(crate) buf: &'b [u8],
     pub(crate) iter: Iter<'b, HeaderIndices>,
 }
 
+impl<'b> HeaderIterator<'b> {
+    /// Returns the value of the first header `name` (case-insensitive)
+    #[inline]
+    pub fn get(&self, name: &str) -> Option<HeaderValue<'b>> {
+        self.clone().find(|h| h.name == name).map(|h| h.value)
+    }
+
+    /// Returns the values of all headers `name` (case-insensitive) in order of appearance
+    #[inline]
+    pub fn get_all<'n>(&self, name: &'n str) -> impl Iterator<Item = HeaderValue<'b>> + 'n
+    where
+        'b: 'n,
+    {
+        self.clone()
+            .filter(move |h| h.name == name)
+            .map(|h| h.value)
+    }
+
+    /// Returns the elements of all comma separated lists in headers `name`,
+    /// e.g. "a, b" and "c" in two 'Allow' headers yield "a", "b" and "c"
+    #[inline]
+    pub fn get_list<'n>(&self, name: &'n str) -> impl Iterator<Item = HeaderValue<'b>> + 'n
+    where
+        'b: 'n,
+    {
+        self.get_all(name).flat_map(|v| v.split_list())
+    }
+
+    /// Returns `true` if a header `name` (case-insensitive) is present
+    #[inline]
+    pub fn contains(&self, name: &str) -> bool {
+        self.clone().any(|h| h.name == name)
+    }
+}
+
 impl<'b> Iterator for HeaderIterator<'b> {
     type Item = Header<'b>;
 
@@ -25,3 +60,32 @@ impl<'b> Iterator for HeaderIterator<'b> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::HeaderIndicesList;
+
+    #[test]
+    fn test_lookup() {
+        let buf = b"Host: example.com\r\nAllow: 204, 206\r\nallow: trailers\r\n\r\n";
+        let mut indices = HeaderIndicesList::default();
+        crate::decoder::decode_trailer(buf, &mut indices, 128).unwrap();
+        let headers = HeaderIterator {
+            buf,
+            iter: indices.vec.iter(),
+        };
+
+        assert_eq!(headers.get("HOST").unwrap().to_str(), Ok("example.com"));
+        assert_eq!(headers.get("allow").unwrap().as_bytes(), b"204, 206");
+        assert!(headers.get("Content-Type").is_none());
+        assert!(headers.contains("Host"));
+        assert!(!headers.contains("Hos"));
+
+        let all: Vec<_> = headers.get_all("Allow").map(|v| v.as_bytes()).collect();
+        assert_eq!(all, [b"204, 206".as_ref(), b"trailers"]);
+        let list: Vec<_> = headers.get_list("Allow").map(|v| v.as_bytes()).collect();
+        assert_eq!(list, [b"204".as_ref(), b"206", b"trailers"]);
+        assert_eq!(headers.count(), 3);
+    }
+}
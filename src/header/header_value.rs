@@ -1,3 +1,6 @@
+use crate::errors::HeaderValueError;
+use std::str::{self, FromStr};
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct HeaderValue<'b> {
     /// The value is any ASCII
@@ -17,4 +20,80 @@ impl<'b> HeaderValue<'b> {
     {
         self.value
     }
+
+    /// Returns the value as a string, failing for non UTF-8 values
+    #[inline]
+    pub fn to_str(&self) -> Result<&'b str, HeaderValueError> {
+        str::from_utf8(self.value).map_err(|_| HeaderValueError)
+    }
+
+    /// Parses the value with surrounding whitespace trimmed, e.g. `Content-Length` as `u64`
+    #[inline]
+    pub fn parse<T: FromStr>(&self) -> Result<T, HeaderValueError> {
+        self.to_str()?.trim().parse().map_err(|_| HeaderValueError)
+    }
+
+    /// Splits a comma separated list into its non-empty elements with surrounding
+    /// whitespace trimmed, commas inside quoted strings don't split
+    pub fn split_list(&self) -> impl Iterator<Item = HeaderValue<'b>> {
+        let value = self.value;
+        let mut start = 0;
+        let mut quoted = false;
+        let mut escaped = false;
+        let mut elements = Vec::new();
+        for (i, &b) in value.iter().enumerate() {
+            if escaped {
+                escaped = false;
+            } else if quoted && b == b'\\' {
+                escaped = true;
+            } else if b == b'"' {
+                quoted = !quoted;
+            } else if b == b',' && !quoted {
+                elements.push(&value[start..i]);
+                start = i + 1;
+            }
+        }
+        elements.push(&value[start..]);
+        elements
+            .into_iter()
+            .map(<[u8]>::trim_ascii)
+            .filter(|e| !e.is_empty())
+            .map(HeaderValue::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_str_parse() {
+        assert_eq!(HeaderValue::new(b"text/html").to_str(), Ok("text/html"));
+        assert_eq!(HeaderValue::new(b"\xff").to_str(), Err(HeaderValueError));
+        assert_eq!(HeaderValue::new(b" 42 ").parse::<u64>(), Ok(42));
+        assert_eq!(
+            HeaderValue::new(b"4x").parse::<u64>(),
+            Err(HeaderValueError)
+        );
+    }
+
+    #[test]
+    fn test_split_list() {
+        let split = |v: &'static [u8]| -> Vec<&[u8]> {
+            HeaderValue::new(v)
+                .split_list()
+                .map(|e| e.as_bytes())
+                .collect()
+        };
+        assert_eq!(
+            split(b"204, 206 ,trailers"),
+            [&b"204"[..], b"206", b"trailers"]
+        );
+        assert_eq!(split(b" , a,,"), [&b"a"[..]]);
+        assert_eq!(
+            split(br#"a="x, y", b="\", c""#),
+            [&br#"a="x, y""#[..], br#"b="\", c""#]
+        );
+        assert!(split(b"").is_empty());
+    }
 }
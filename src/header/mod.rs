@@ -3,6 +3,7 @@ mod header_iterator;
 mod header_name;
 mod header_value;
 
+pub use crate::errors::HeaderValueError;
 pub(crate) use header_indices::*;
 pub use header_iterator::*;
 pub use header_name::*;
@@ -1,3 +1,5 @@
+#![cfg_attr(docsrs, feature(doc_cfg))]
+
 pub(crate) mod common;
 #[cfg(not(fuzzing))]
 #[allow(dead_code)]
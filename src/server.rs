@@ -1,12 +1,36 @@
 mod connection;
 pub use connection::*;
 
+mod block_page;
+mod body;
 mod config;
 mod config_builder;
+#[cfg(feature = "config")]
+mod file_cfg;
+mod options_response;
 mod request_context;
 mod tcp_acceptor;
+#[cfg(feature = "tls")]
+mod tls_cfg;
 
+pub use crate::decoder::EncapsulatedEntity;
+#[cfg(feature = "config")]
+#[cfg_attr(docsrs, doc(cfg(feature = "config")))]
+pub use crate::errors::FileCfgError;
+#[cfg(feature = "tls")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tls")))]
+pub use crate::errors::TlsCfgError;
+pub use crate::errors::{BodySinkClosedError, DecoderError, OptionsError};
+pub use block_page::*;
+pub use body::*;
 pub use config::*;
 pub use config_builder::*;
+#[cfg(feature = "config")]
+#[cfg_attr(docsrs, doc(cfg(feature = "config")))]
+pub use file_cfg::*;
+pub use options_response::*;
 pub use request_context::*;
 pub use tcp_acceptor::*;
+#[cfg(feature = "tls")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tls")))]
+pub use tls_cfg::*;
@@ -0,0 +1,94 @@
+use std::borrow::Cow;
+
+const DEFAULT_TEMPLATE: &str = "<!DOCTYPE html>\n\
+<html>\n\
+<head><title>Access Denied</title></head>\n\
+<body>\n\
+<h1>Access Denied</h1>\n\
+<p>Access to <b>{url}</b> is blocked by policy (category: {category}).</p>\n\
+<p>Client: {client_ip}</p>\n\
+</body>\n\
+</html>\n";
+
+/// HTML page template for blocked requests
+///
+/// The placeholders `{url}`, `{category}` and `{client_ip}` are replaced
+/// with HTML-escaped values when rendered.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BlockPage {
+    template: Cow<'static, str>,
+}
+
+impl BlockPage {
+    #[inline]
+    pub fn new(template: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            template: template.into(),
+        }
+    }
+
+    pub fn render(&self, url: &str, category: &str, client_ip: &str) -> String {
+        let mut out = String::with_capacity(self.template.len() + url.len());
+        let mut rest = &*self.template;
+
+        while let Some(pos) = rest.find('{') {
+            out.push_str(&rest[..pos]);
+            rest = &rest[pos..];
+
+            let (value, len) = if rest.starts_with("{url}") {
+                (url, 5)
+            } else if rest.starts_with("{category}") {
+                (category, 10)
+            } else if rest.starts_with("{client_ip}") {
+                (client_ip, 11)
+            } else {
+                out.push('{');
+                rest = &rest[1..];
+                continue;
+            };
+            escape_html(&mut out, value);
+            rest = &rest[len..];
+        }
+        out.push_str(rest);
+
+        out
+    }
+}
+
+impl Default for BlockPage {
+    #[inline]
+    fn default() -> Self {
+        Self::new(DEFAULT_TEMPLATE)
+    }
+}
+
+fn escape_html(out: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let page = BlockPage::new("{url} {category} {client_ip} {other} {");
+        assert_eq!(
+            page.render("http://a.b/?q=<x>&y", "adult", "10.0.0.1"),
+            "http://a.b/?q=&lt;x&gt;&amp;y adult 10.0.0.1 {other} {"
+        );
+
+        let page = BlockPage::default();
+        let html = page.render("http://a.b/\"'", "", "");
+        assert!(html.contains("<b>http://a.b/&quot;&#39;</b>"));
+    }
+}
@@ -0,0 +1,58 @@
+use crate::errors::BodySinkClosedError;
+use bytes::Bytes;
+use tokio::sync::{mpsc, oneshot};
+
+pub(crate) const BODY_CHANNEL_CAP: usize = 16;
+
+/// Stream of incoming encapsulated HTTP body chunks
+#[derive(Debug)]
+pub struct BodyStream {
+    rx: mpsc::Receiver<Bytes>,
+}
+
+impl BodyStream {
+    #[inline]
+    pub(crate) fn new(rx: mpsc::Receiver<Bytes>) -> Self {
+        Self { rx }
+    }
+
+    /// Returns the next body chunk, or `None` once the entire body has been received
+    #[inline]
+    pub async fn next(&mut self) -> Option<Bytes> {
+        self.rx.recv().await
+    }
+}
+
+/// Sink of outgoing adapted HTTP body chunks
+///
+/// The adapted body ends when the sink is dropped.
+#[derive(Debug)]
+pub struct BodySink {
+    tx: mpsc::Sender<Bytes>,
+    trailer_tx: oneshot::Sender<http::HeaderMap>,
+}
+
+impl BodySink {
+    #[inline]
+    pub(crate) fn new(
+        tx: mpsc::Sender<Bytes>,
+        trailer_tx: oneshot::Sender<http::HeaderMap>,
+    ) -> Self {
+        Self { tx, trailer_tx }
+    }
+
+    /// Sends a body chunk to the client, waiting while the outgoing queue is full
+    #[inline]
+    pub async fn send(&self, chunk: Bytes) -> Result<(), BodySinkClosedError> {
+        self.tx.send(chunk).await.map_err(|_| BodySinkClosedError)
+    }
+
+    /// Ends the body, sending `trailers` in the ICAP trailer
+    ///
+    /// The trailers are sent only if the client sent 'Allow: trailers'
+    /// and their names were declared with `ReqCtx::declare_icap_trailer`.
+    #[inline]
+    pub fn finish_with_trailers(self, trailers: http::HeaderMap) {
+        let _ = self.trailer_tx.send(trailers);
+    }
+}
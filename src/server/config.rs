@@ -1,12 +1,99 @@
-use crate::server::ServerCfgBuilder;
+use crate::server::{OptionsResponse, ServerCfgBuilder};
+use std::time::Duration;
 
-#[derive(Debug, Clone, Default)]
+pub(crate) const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+pub(crate) const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(10);
+pub(crate) const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+pub(crate) const DEFAULT_RBUF_CAP: usize = 8 * 1024;
+pub(crate) const DEFAULT_HTTP_BUF_CAP: usize = DEFAULT_RBUF_CAP;
+pub(crate) const DEFAULT_BODY_BUF_LIMIT: usize = 64 * 1024;
+pub(crate) const DEFAULT_MAX_HEADERS: usize = 128;
+
+#[derive(Debug, Clone)]
 #[non_exhaustive]
-pub struct ServerCfg {}
+pub struct ServerCfg {
+    pub(crate) options: OptionsResponse,
+    pub(crate) max_requests: Option<usize>,
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) idle_timeout: Duration,
+    pub(crate) read_timeout: Duration,
+    pub(crate) shutdown_timeout: Duration,
+    pub(crate) rbuf_capacity: usize,
+    pub(crate) http_buf_capacity: usize,
+    pub(crate) body_buf_limit: usize,
+    pub(crate) max_headers: usize,
+}
+
+impl Default for ServerCfg {
+    fn default() -> Self {
+        ServerCfg {
+            options: OptionsResponse::default(),
+            max_requests: None,
+            max_connections: None,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            rbuf_capacity: DEFAULT_RBUF_CAP,
+            http_buf_capacity: DEFAULT_HTTP_BUF_CAP,
+            body_buf_limit: DEFAULT_BODY_BUF_LIMIT,
+            max_headers: DEFAULT_MAX_HEADERS,
+        }
+    }
+}
 
 impl ServerCfg {
     #[inline]
     pub fn builder() -> ServerCfgBuilder {
         ServerCfgBuilder::default()
     }
+
+    #[inline]
+    pub fn options(&self) -> &OptionsResponse {
+        &self.options
+    }
+
+    #[inline]
+    pub fn max_requests(&self) -> Option<usize> {
+        self.max_requests
+    }
+
+    #[inline]
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    #[inline]
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    #[inline]
+    pub fn read_timeout(&self) -> Duration {
+        self.read_timeout
+    }
+
+    #[inline]
+    pub fn shutdown_timeout(&self) -> Duration {
+        self.shutdown_timeout
+    }
+
+    #[inline]
+    pub fn rbuf_capacity(&self) -> usize {
+        self.rbuf_capacity
+    }
+
+    #[inline]
+    pub fn http_buf_capacity(&self) -> usize {
+        self.http_buf_capacity
+    }
+
+    #[inline]
+    pub fn body_buf_limit(&self) -> usize {
+        self.body_buf_limit
+    }
+
+    #[inline]
+    pub fn max_headers(&self) -> usize {
+        self.max_headers
+    }
 }
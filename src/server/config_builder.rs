@@ -1,12 +1,95 @@
-use crate::server::ServerCfg;
-use std::sync::Arc;
+use crate::server::{OptionsResponse, ServerCfg};
+use std::{sync::Arc, time::Duration};
 
-#[derive(Debug, Default)]
+/// The minimal capacity of the read buffer, at least this many bytes are read at once
+const MIN_RBUF_CAP: usize = 1024;
+
+#[derive(Debug, Default, Clone)]
 #[non_exhaustive]
-pub struct ServerCfgBuilder {}
+pub struct ServerCfgBuilder {
+    cfg: ServerCfg,
+}
 
 impl ServerCfgBuilder {
+    /// Sets the OPTIONS response advertised by the service
+    #[inline]
+    pub fn options(mut self, options: OptionsResponse) -> Self {
+        self.cfg.options = options;
+        self
+    }
+
+    /// Sets the number of requests served on a connection,
+    /// the last response is sent with 'Connection: close'
+    #[inline]
+    pub fn max_requests(mut self, max_requests: usize) -> Self {
+        self.cfg.max_requests = Some(max_requests);
+        self
+    }
+
+    /// Sets the number of concurrent connections accepted by the server, at least 1,
+    /// connections above it are answered with '503 Service Overloaded' and closed
+    ///
+    /// The limit is advertised in the 'Max-Connections' OPTIONS header,
+    /// unless the OPTIONS response sets a lower one.
+    #[inline]
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.cfg.max_connections = Some(max_connections.max(1));
+        self
+    }
+
+    /// Sets how long to wait for the next request before closing the connection
+    #[inline]
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.cfg.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets how long to wait for the rest of a started request
+    #[inline]
+    pub fn read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.cfg.read_timeout = read_timeout;
+        self
+    }
+
+    /// Sets how long active connections may finish their requests on shutdown,
+    /// before they are closed
+    #[inline]
+    pub fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.cfg.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// Sets the initial capacity of the read buffer, and the size by which it grows,
+    /// at least 1 KiB
+    #[inline]
+    pub fn rbuf_capacity(mut self, capacity: usize) -> Self {
+        self.cfg.rbuf_capacity = capacity.max(MIN_RBUF_CAP);
+        self
+    }
+
+    /// Sets the initial capacity of the buffer of the encoded HTTP message
+    #[inline]
+    pub fn http_buf_capacity(mut self, capacity: usize) -> Self {
+        self.cfg.http_buf_capacity = capacity;
+        self
+    }
+
+    /// Sets the body size passed to the handler without preview before the body is complete,
+    /// requests with a larger 'Preview' are rejected with 400
+    #[inline]
+    pub fn body_buf_limit(mut self, limit: usize) -> Self {
+        self.cfg.body_buf_limit = limit;
+        self
+    }
+
+    /// Sets the maximal number of headers in ICAP and encapsulated HTTP headers and trailers
+    #[inline]
+    pub fn max_headers(mut self, max_headers: usize) -> Self {
+        self.cfg.max_headers = max_headers;
+        self
+    }
+
     pub fn build(self) -> Arc<ServerCfg> {
-        Arc::new(ServerCfg {})
+        Arc::new(self.cfg)
     }
 }
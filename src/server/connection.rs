@@ -1,28 +1,28 @@
 use crate::{
     common::Id,
-    decoder::{decode_chunk_header, DecodingStatus},
-    errors::ConnectionError,
-    server::{AdaptationDecision::*, ReqCtx, ReqCtxBox, DEFAULT_IS_TAG, RBUF_CAP},
+    decoder::{decode_chunk_header, decode_trailer, ChunkHdr, DecodingStatus},
+    errors::{ConnectionError, DecoderError},
+    header::HeaderIndicesList,
+    server::{AdaptationDecision::*, ReqCtx, ReqCtxBox, ServerCfg},
     service::IcapService,
     Method, Version,
 };
-use bytes::{BufMut, BytesMut};
-use http::StatusCode;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http::{HeaderValue, StatusCode};
 use std::{
-    fmt::Write,
+    fmt::{self, Write},
     io::{self, ErrorKind},
     slice,
+    sync::Arc,
     time::Duration,
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
+    sync::watch,
 };
 use tracing::{debug, error, info, instrument, trace, warn};
 
-const READ_TIMEOUT: Duration = Duration::from_secs(10);
-const CONNECTION_TIMEOUT: Duration = Duration::from_secs(60);
-
 #[derive(Debug)]
 enum ProcessingDecision {
     Continue(ReqCtxBox),
@@ -32,36 +32,59 @@ enum ProcessingDecision {
 type ConnectionResult = Result<ProcessingDecision, ConnectionError>;
 
 #[derive(Debug)]
-pub struct Connection<S> {
+enum BodyEvent {
+    Passed,
+    StreamClosed,
+    Out(Option<Bytes>),
+}
+
+/// An ICAP connection over a TCP or a TLS stream
+#[derive(Debug)]
+pub struct Connection<S, T = TcpStream> {
     pub id: Id,
-    sock: TcpStream,
+    sock: T,
     wbuf: BytesMut,
+    cfg: Arc<ServerCfg>,
     svc: S,
+    shutdown: Option<watch::Receiver<bool>>,
+    /// ISTag of the service of the current message, sent in error responses
+    is_tag: HeaderValue,
 }
 
-impl<S> Connection<S>
+impl<S, T> Connection<S, T>
 where
     S: IcapService,
+    T: AsyncRead + AsyncWrite + Unpin,
     <S as IcapService>::OPF: Send,
     <S as IcapService>::RQF: Send,
     <S as IcapService>::RSF: Send,
 {
-    pub fn new(id: Id, sock: TcpStream, svc: S) -> Self {
+    /// Creates a connection over `sock`, socket options such as TCP_NODELAY
+    /// are left to the caller
+    pub fn new(id: Id, sock: T, svc: S) -> Self {
+        let cfg = svc.server_cfg();
         Connection {
             id,
             sock,
             wbuf: BytesMut::with_capacity(512),
+            is_tag: cfg.options().is_tag().clone(),
+            cfg,
             svc,
+            shutdown: None,
         }
     }
 
+    /// Sets the receiver of the server shutdown signal, once it turns `true`
+    /// an idle connection is closed, and an active one after the current message
+    pub(crate) fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
     #[instrument(name = "connection", skip(self), fields(id = %self.id))]
     pub async fn process(&mut self) {
-        if self.sock.set_nodelay(true).is_err() {
-            error!("failed to set TCP_NODELAY");
-        }
-
-        let mut ctx = ReqCtx::new_box();
+        let mut ctx = ReqCtx::new_box(self.cfg.clone());
+        ctx.shutdown = self.shutdown.take();
         loop {
             ctx.msgs_cnt += 1;
             ctx = match self.process_message(ctx).await {
@@ -72,6 +95,10 @@ where
                     break;
                 }
             };
+            if ctx.is_last_msg() {
+                debug!("closing connection after the last message");
+                break;
+            }
             ctx.clear();
 
             // allow other connections to be scheduled
@@ -83,8 +110,32 @@ where
         }
     }
 
+    /// Answers a connection over the server limit with '503 Service Overloaded'
+    /// and closes it, the unread request is discarded to not reset the connection
+    /// before the client receives the response
+    #[instrument(name = "connection", skip(self), fields(id = %self.id))]
+    pub(crate) async fn reject_overloaded(&mut self) {
+        if let Err(e) = self.send_status(StatusCode::SERVICE_UNAVAILABLE).await {
+            warn!(err=%e, "failed to send status");
+            return;
+        }
+        if let Err(e) = self.sock.shutdown().await {
+            warn!(err=%e, "socket.shutdown failed");
+            return;
+        }
+        let mut buf = [0u8; 1024];
+        let drain = async { while matches!(self.sock.read(&mut buf).await, Ok(n) if n > 0) {} };
+        if tokio::time::timeout(self.cfg.read_timeout, drain)
+            .await
+            .is_err()
+        {
+            debug!("client did not close the rejected connection");
+        }
+    }
+
     #[instrument(name = "message", skip(self, ctx), fields(n = ctx.msgs_cnt), err)]
     async fn process_message(&mut self, mut ctx: ReqCtxBox) -> ConnectionResult {
+        self.is_tag = self.cfg.options().is_tag().clone();
         ctx = match self.init_ctx(ctx).await {
             Ok(ctx) => ctx,
             Err(ConnectionError::Decoder(e)) => {
@@ -98,23 +149,65 @@ where
             _ => return Ok(ProcessingDecision::Shutdown),
         };
 
+        if self.cfg.max_requests.is_some_and(|max| ctx.msgs_cnt >= max) {
+            debug!("reached max requests per connection");
+            ctx.close = true;
+        }
+
+        // the whole preview is buffered before the handler is called
+        if ctx.preview.is_some_and(|n| n > self.cfg.body_buf_limit) {
+            warn!(
+                preview = ctx.preview,
+                "preview exceeds the body buffer limit"
+            );
+            return self.send_status(StatusCode::BAD_REQUEST).await;
+        }
+
+        if !self.svc.has_service(&ctx.icap_req.uri) {
+            warn!(uri = %ctx.icap_req.uri, "service not found");
+            return self.send_status(StatusCode::NOT_FOUND).await;
+        }
+        ctx.cfg = self.svc.service_cfg(&ctx.icap_req.uri);
+        self.is_tag = ctx.cfg.options().is_tag().clone();
+
         ctx = match self.recv_missing_header_bytes(ctx).await? {
             ProcessingDecision::Continue(ctx) => ctx,
             ProcessingDecision::Shutdown => return Ok(ProcessingDecision::Shutdown),
         };
 
+        // all header bytes are in ctx.rbuf now, header indices are offsets into it,
+        // so they stay valid while the body is received after the headers
+        if let Err(e) = ctx.parse_entities() {
+            error!(err = %e, "parse_entities failed");
+            return self.send_status(StatusCode::BAD_REQUEST).await;
+        }
+
+        let ignored = ctx.icap_req.method.is_any_req() && ctx.is_transfer_ignored();
         if ctx.icap_req.method.is_any_req() && !ctx.null_body {
-            ctx = match self.recv_preview_zero_chunk(ctx).await? {
-                ProcessingDecision::Continue(ctx) => ctx,
-                ProcessingDecision::Shutdown => return Ok(ProcessingDecision::Shutdown),
+            let res = if !ignored {
+                self.recv_body(&mut ctx).await
+            } else if ctx.can_204() {
+                self.drain_body(&mut ctx).await
+            } else {
+                // the body is streamed back by the echo
+                Ok(())
             };
+            if let Err(e) = res {
+                return self.body_error(e).await;
+            }
+        } else {
+            if ctx.icap_req.method.is_any_req() && ctx.expect_trailer {
+                if let Err(e) = self.recv_icap_trailer(&mut ctx).await {
+                    return self.body_error(e).await;
+                }
+            }
+            ctx.body_complete = true;
         }
 
-        // now that all data is in ctx.rbuf, it is not expected to be reallocated
-        // so we can parse the headers and build header indices
-        if let Err(e) = ctx.parse_entities() {
-            error!(err = %e, "parse_entities failed");
-            return self.send_status(StatusCode::BAD_REQUEST).await;
+        if ignored {
+            debug!("ignored file extension, skipping handler");
+            ctx.set_decision(NoAdaptation);
+            return self.process_decision(ctx).await;
         }
 
         match ctx.icap_req.method {
@@ -132,7 +225,7 @@ where
                 return self.send_status(StatusCode::INTERNAL_SERVER_ERROR).await;
             }
         };
-        ctx.ensure_options_headers();
+        ctx.ensure_options_headers(self.cfg.max_connections);
         self.wbuf.clear();
         write!(
             self.wbuf,
@@ -146,29 +239,45 @@ where
         Ok(ProcessingDecision::Continue(ctx))
     }
 
-    async fn process_reqmod(&mut self, ctx: ReqCtxBox) -> ConnectionResult {
-        let ctx = match self.svc.handle_reqmod(ctx).await {
-            Ok(ctx) => ctx,
-            Err(e) => {
-                error!(err = %e, "handle_reqmod failed");
-                return self.send_status(StatusCode::INTERNAL_SERVER_ERROR).await;
+    async fn process_reqmod(&mut self, mut ctx: ReqCtxBox) -> ConnectionResult {
+        loop {
+            ctx = match self.svc.handle_reqmod(ctx).await {
+                Ok(ctx) => ctx,
+                Err(e) => {
+                    error!(err = %e, "handle_reqmod failed");
+                    return self.send_status(StatusCode::INTERNAL_SERVER_ERROR).await;
+                }
+            };
+            if ctx.decision != Some(NeedFullBody) || ctx.body_complete {
+                return self.process_decision(ctx).await;
             }
-        };
-        self.process_decision(ctx).await
+            if let Err(e) = self.recv_full_body(&mut ctx).await {
+                return self.body_error(e).await;
+            }
+            ctx.decision = None;
+        }
     }
 
-    async fn process_respmod(&mut self, ctx: ReqCtxBox) -> ConnectionResult {
-        let ctx = match self.svc.handle_respmod(ctx).await {
-            Ok(ctx) => ctx,
-            Err(e) => {
-                error!(err = %e, "handle_respmod failed");
-                return self.send_status(StatusCode::INTERNAL_SERVER_ERROR).await;
+    async fn process_respmod(&mut self, mut ctx: ReqCtxBox) -> ConnectionResult {
+        loop {
+            ctx = match self.svc.handle_respmod(ctx).await {
+                Ok(ctx) => ctx,
+                Err(e) => {
+                    error!(err = %e, "handle_respmod failed");
+                    return self.send_status(StatusCode::INTERNAL_SERVER_ERROR).await;
+                }
+            };
+            if ctx.decision != Some(NeedFullBody) || ctx.body_complete {
+                return self.process_decision(ctx).await;
             }
-        };
-        self.process_decision(ctx).await
+            if let Err(e) = self.recv_full_body(&mut ctx).await {
+                return self.body_error(e).await;
+            }
+            ctx.decision = None;
+        }
     }
 
-    async fn process_decision(&mut self, ctx: ReqCtxBox) -> ConnectionResult {
+    async fn process_decision(&mut self, mut ctx: ReqCtxBox) -> ConnectionResult {
         let decision = match ctx.decision {
             Some(d) => d,
             None => {
@@ -176,10 +285,27 @@ where
                 return self.send_status(StatusCode::INTERNAL_SERVER_ERROR).await;
             }
         };
+        // without 204 or 206 the original body is streamed back to the client
+        let echoed = (decision == NoAdaptation && !ctx.can_204())
+            || (decision == AppendHeaders && !ctx.can_206());
+        if !ctx.body_complete && !echoed && decision != TransformBody && !ctx.is_preview() {
+            // outside preview the client sends the rest of the body anyway,
+            // it has to be consumed before the next message
+            if let Err(e) = self.drain_body(&mut ctx).await {
+                return self.body_error(e).await;
+            }
+        }
         match decision {
-            NoAdaptation => self.send_204(ctx).await,
+            NoAdaptation if ctx.can_204() => self.send_204(ctx).await,
+            NoAdaptation => self.echo(ctx).await,
             AppendHeaders => self.append_headers(ctx).await,
             CustomResponse => self.custom_response(ctx).await,
+            ReplaceBody => self.replace_body(ctx).await,
+            TransformBody => self.transform_body(ctx).await,
+            NeedFullBody => {
+                error!("full body requested, but the body is already complete");
+                self.send_status(StatusCode::INTERNAL_SERVER_ERROR).await
+            }
         }
     }
 
@@ -198,55 +324,259 @@ where
         Ok(ProcessingDecision::Continue(ctx))
     }
 
+    /// Sends the unmodified encapsulated message in a 200 response
+    async fn echo(&mut self, mut ctx: ReqCtxBox) -> ConnectionResult {
+        trace!("204 is not allowed, echoing the original message");
+        ctx.out_http_headers.clear();
+        ctx.out_http_removed.clear();
+        ctx.out_http_replaced.clear();
+        ctx.out_http_method = None;
+        ctx.out_http_uri = None;
+        ctx.out_http_body_prefix = None;
+        ctx.original_body_offset = 0;
+        self.append_headers(ctx).await
+    }
+
     async fn append_headers(&mut self, mut ctx: ReqCtxBox) -> ConnectionResult {
         ctx.ensure_response_headers();
-        self.wbuf.clear();
         ctx.http_buf.clear();
 
-        let ee = match ctx.icap_req.method {
-            Method::ReqMod => {
-                write!(
-                    ctx.http_buf,
-                    "{} {} {:?}\r\n",
-                    ctx.http_req.method, ctx.http_req.uri, ctx.http_req.version
-                )?;
-                ctx.http_req.headers.encode(&ctx.rbuf, &mut ctx.http_buf);
-                "req"
-            }
-            Method::RespMod => {
-                write!(
-                    ctx.http_buf,
-                    "{:?} {}\r\n",
-                    ctx.http_res.version, ctx.http_res.status
-                )?;
-                ctx.http_res.headers.encode(&ctx.rbuf, &mut ctx.http_buf);
-                "res"
-            }
-            _ => panic!("should not get here"),
+        let prefix = ctx.out_http_body_prefix.take().unwrap_or_default();
+        let orig_off = ctx.original_body_offset;
+
+        // a prefix of a different length than the replaced part changes the body length
+        let resized = !ctx.null_body && prefix.len() != orig_off;
+        let content_len = match ctx.original_body_len() {
+            Some(len) if len < orig_off => {
+                error!(
+                    len,
+                    orig_off, "original body offset exceeds the body length"
+                );
+                return self.send_status(StatusCode::INTERNAL_SERVER_ERROR).await;
+            }
+            Some(len) => Some(len - orig_off + prefix.len()),
+            None => None,
         };
 
-        write_headers_map(&mut ctx.http_buf, &ctx.out_http_headers);
+        let ee = if resized {
+            let ee = encode_http_hdr(&mut ctx, &["Content-Length"])?;
+            if let Some(len) = content_len {
+                write!(ctx.http_buf, "Content-Length: {}\r\n", len)?;
+            }
+            ee
+        } else {
+            encode_http_hdr(&mut ctx, &[])?
+        };
         ctx.http_buf.extend_from_slice(b"\r\n");
 
         let body_off = ctx.http_buf.len();
 
-        if !ctx.null_body {
-            ctx.http_buf
-                .extend_from_slice(b"0; use-original-body=0\r\n\r\n");
+        if ctx.null_body {
+            let enc = cds::aformat!(128, "{}-hdr=0, null-body={}", ee, body_off)?;
+            self.send_response(&ctx, StatusCode::OK, enc.as_str())
+                .await?;
+        } else if ctx.can_206() {
+            write_chunk(&mut ctx.http_buf, &prefix)?;
+            write!(ctx.http_buf, "0; use-original-body={}\r\n\r\n", orig_off)?;
+            let enc = cds::aformat!(128, "{}-hdr=0, {}-body={}", ee, ee, body_off)?;
+            self.send_response(&ctx, StatusCode::PARTIAL_CONTENT, enc.as_str())
+                .await?;
+        } else {
+            // 100 Continue must precede the final response
+            if ctx.is_preview() && !ctx.body_complete {
+                self.send_continue(&mut ctx).await?;
+            }
+            write_chunk(&mut ctx.http_buf, &prefix)?;
+            let enc = cds::aformat!(128, "{}-hdr=0, {}-body={}", ee, ee, body_off)?;
+            self.send_response(&ctx, StatusCode::OK, enc.as_str())
+                .await?;
+            self.send_original_body(&mut ctx, orig_off).await?;
         }
+        self.send_trailer(&ctx, None).await?;
 
-        let (enc, status) = if !ctx.null_body {
-            (
-                cds::aformat!(128, "{}-hdr=0, {}-body={}", ee, ee, body_off)?,
-                StatusCode::PARTIAL_CONTENT,
-            )
-        } else {
-            (
-                cds::aformat!(128, "{}-hdr=0, null-body={}", ee, body_off)?,
-                StatusCode::OK,
-            )
+        Ok(ProcessingDecision::Continue(ctx))
+    }
+
+    async fn replace_body(&mut self, mut ctx: ReqCtxBox) -> ConnectionResult {
+        let body = match ctx.out_http_body.take() {
+            Some(b) => b,
+            None => {
+                error!("replacement body not set");
+                return self.send_status(StatusCode::INTERNAL_SERVER_ERROR).await;
+            }
         };
 
+        ctx.ensure_response_headers();
+        ctx.http_buf.clear();
+
+        let ee = encode_http_hdr(&mut ctx, &["Content-Length", "Transfer-Encoding"])?;
+        write!(ctx.http_buf, "Content-Length: {}\r\n\r\n", body.len())?;
+
+        let body_off = ctx.http_buf.len();
+        write_chunked_body(&mut ctx.http_buf, &body)?;
+
+        let enc = cds::aformat!(128, "{}-hdr=0, {}-body={}", ee, ee, body_off)?;
+        self.send_response(&ctx, StatusCode::OK, enc.as_str())
+            .await?;
+        self.send_trailer(&ctx, None).await?;
+
+        Ok(ProcessingDecision::Continue(ctx))
+    }
+
+    async fn transform_body(&mut self, mut ctx: ReqCtxBox) -> ConnectionResult {
+        let (in_tx, mut out_rx) = match (ctx.body_in_tx.take(), ctx.body_out_rx.take()) {
+            (Some(tx), Some(rx)) => (tx, rx),
+            _ => {
+                error!("body transformation channels not set");
+                return self.send_status(StatusCode::INTERNAL_SERVER_ERROR).await;
+            }
+        };
+
+        // 100 Continue must precede the final response
+        if ctx.is_preview() && !ctx.body_complete {
+            self.send_continue(&mut ctx).await?;
+        }
+
+        ctx.ensure_response_headers();
+        ctx.http_buf.clear();
+
+        let ee = encode_http_hdr(&mut ctx, &["Content-Length", "Transfer-Encoding"])?;
+        ctx.http_buf.extend_from_slice(b"\r\n");
+
+        let enc = cds::aformat!(128, "{}-hdr=0, {}-body={}", ee, ee, ctx.http_buf.len())?;
+        self.send_response(&ctx, StatusCode::OK, enc.as_str())
+            .await?;
+
+        let mut in_tx = Some(in_tx);
+        let mut pending: Option<Bytes> = None;
+        loop {
+            if pending.is_none() && !ctx.body.is_empty() {
+                pending = Some(ctx.body.split().freeze());
+            }
+            if in_tx.is_none() {
+                // the handler has dropped its body stream
+                pending = None;
+            }
+            if pending.is_none() {
+                if !ctx.body_complete {
+                    if self.recv_body_part(&mut ctx).await?.is_some() {
+                        ctx.body_complete = true;
+                    }
+                    continue;
+                }
+                // the entire body has been passed to the handler, end its body stream
+                in_tx = None;
+            }
+
+            let ev = tokio::select! {
+                ev = async {
+                    match (&in_tx, &mut pending) {
+                        (Some(tx), pending @ Some(_)) => match tx.reserve().await {
+                            Ok(permit) => {
+                                permit.send(pending.take().unwrap());
+                                BodyEvent::Passed
+                            }
+                            Err(_) => BodyEvent::StreamClosed,
+                        },
+                        _ => std::future::pending().await,
+                    }
+                } => ev,
+                chunk = out_rx.recv() => BodyEvent::Out(chunk),
+            };
+
+            match ev {
+                BodyEvent::Passed => (),
+                BodyEvent::StreamClosed => in_tx = None,
+                BodyEvent::Out(Some(chunk)) => self.send_chunk(&chunk).await?,
+                BodyEvent::Out(None) => break,
+            }
+        }
+
+        drop(in_tx);
+        if !ctx.body_complete {
+            self.drain_body(&mut ctx).await?;
+        }
+        ctx.body.clear();
+        self.sock.write_all(b"0\r\n\r\n").await?;
+        trace!("sent transformed body");
+
+        let trailers = ctx
+            .body_trailer_rx
+            .take()
+            .and_then(|mut rx| rx.try_recv().ok());
+        self.send_trailer(&ctx, trailers.as_ref()).await?;
+
+        Ok(ProcessingDecision::Continue(ctx))
+    }
+
+    /// Streams the original body, without its first `skip` bytes, as it is received,
+    /// followed by the last-chunk
+    async fn send_original_body(
+        &mut self,
+        ctx: &mut ReqCtx,
+        mut skip: usize,
+    ) -> Result<(), ConnectionError> {
+        loop {
+            let n = skip.min(ctx.body.len());
+            ctx.body.advance(n);
+            skip -= n;
+            if !ctx.body.is_empty() {
+                let data = ctx.body.split();
+                self.send_chunk(&data).await?;
+            }
+            if ctx.body_complete {
+                break;
+            }
+            if self.recv_body_part(ctx).await?.is_some() {
+                ctx.body_complete = true;
+            }
+        }
+        self.sock.write_all(b"0\r\n\r\n").await?;
+        trace!("sent original body");
+        Ok(())
+    }
+
+    /// Sends the ICAP trailer announced in the response header,
+    /// made of the handler's trailer fields and `extra`
+    async fn send_trailer(
+        &mut self,
+        ctx: &ReqCtx,
+        extra: Option<&http::HeaderMap>,
+    ) -> Result<(), ConnectionError> {
+        if !ctx.send_trailer {
+            return Ok(());
+        }
+        self.wbuf.clear();
+        write_headers_map(&mut self.wbuf, &ctx.out_icap_trailers);
+        if let Some(extra) = extra {
+            write_headers_map(&mut self.wbuf, extra);
+        }
+        self.wbuf.extend_from_slice(b"\r\n");
+        self.sock.write_all(&self.wbuf).await?;
+        trace!("sent ICAP trailer");
+        Ok(())
+    }
+
+    /// Sends `data` as a single chunk of a chunked body
+    async fn send_chunk(&mut self, data: &[u8]) -> Result<(), ConnectionError> {
+        // an empty chunk would terminate the body
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.wbuf.clear();
+        write_chunk(&mut self.wbuf, data)?;
+        self.sock.write_all(&self.wbuf).await?;
+        Ok(())
+    }
+
+    /// Sends an ICAP response header followed by the encapsulated message in `ctx.http_buf`
+    async fn send_response(
+        &mut self,
+        ctx: &ReqCtx,
+        status: StatusCode,
+        enc: &str,
+    ) -> Result<(), ConnectionError> {
+        self.wbuf.clear();
         write!(self.wbuf, "{} {}\r\n", Version::Icap10, status)?;
         write_headers_map(&mut self.wbuf, &ctx.out_icap_headers);
         self.wbuf.extend_from_slice(b"Encapsulated: ");
@@ -255,13 +585,11 @@ where
 
         self.sock.write_all(&self.wbuf).await?;
         self.sock.write_all(&ctx.http_buf).await?;
-
-        Ok(ProcessingDecision::Continue(ctx))
+        Ok(())
     }
 
     async fn custom_response(&mut self, mut ctx: ReqCtxBox) -> ConnectionResult {
         ctx.ensure_response_headers();
-        self.wbuf.clear();
         ctx.http_buf.clear();
 
         let http_status = match ctx.out_http_status {
@@ -284,39 +612,61 @@ where
             }
         };
 
+        let body = ctx.out_http_body.take();
+        if body.is_some() {
+            ctx.out_http_replaced.remove(http::header::CONTENT_LENGTH);
+            ctx.out_http_headers.remove(http::header::CONTENT_LENGTH);
+            ctx.out_http_replaced
+                .remove(http::header::TRANSFER_ENCODING);
+            ctx.out_http_headers.remove(http::header::TRANSFER_ENCODING);
+        }
+
         write!(ctx.http_buf, "{:?} {}\r\n", http_version, http_status)?;
+        write_headers_map(&mut ctx.http_buf, &ctx.out_http_replaced);
         write_headers_map(&mut ctx.http_buf, &ctx.out_http_headers);
+        if let Some(ref body) = body {
+            write!(ctx.http_buf, "Content-Length: {}\r\n", body.len())?;
+        }
         ctx.http_buf.extend_from_slice(b"\r\n");
 
-        write!(self.wbuf, "{} {}\r\n", Version::Icap10, StatusCode::OK)?;
-        write_headers_map(&mut self.wbuf, &ctx.out_icap_headers);
-
-        let enc = cds::aformat!(128, "res-hdr=0, null-body={}\r\n", ctx.http_buf.len())?;
-        self.wbuf.extend_from_slice(b"Encapsulated: ");
-        self.wbuf.extend_from_slice(enc.as_bytes());
-        self.wbuf.extend_from_slice(b"\r\n");
-
-        self.sock.write_all(&self.wbuf).await?;
-        self.sock.write_all(&ctx.http_buf).await?;
+        let body_off = ctx.http_buf.len();
+        let enc = match body {
+            Some(body) => {
+                write_chunked_body(&mut ctx.http_buf, &body)?;
+                cds::aformat!(128, "res-hdr=0, res-body={}", body_off)?
+            }
+            None => cds::aformat!(128, "res-hdr=0, null-body={}", body_off)?,
+        };
+        self.send_response(&ctx, StatusCode::OK, enc.as_str())
+            .await?;
+        self.send_trailer(&ctx, None).await?;
 
         Ok(ProcessingDecision::Continue(ctx))
     }
 
     #[instrument(skip(self, ctx))]
     async fn init_ctx(&mut self, mut ctx: ReqCtxBox) -> Result<ReqCtxBox, ConnectionError> {
-        let mut timeout = CONNECTION_TIMEOUT;
+        let mut timeout = self.cfg.idle_timeout;
         loop {
             match ctx.init()? {
                 DecodingStatus::Complete => {
                     return Ok(ctx);
                 }
                 DecodingStatus::Partial => {
-                    let n = self.recv(&mut ctx.rbuf, timeout).await?;
+                    // a shutdown closes the connection unless a message is being received
+                    let idle = ctx.rbuf.is_empty();
+                    let n = tokio::select! {
+                        res = self.recv(&mut ctx.rbuf, timeout) => res?,
+                        _ = shutdown_signaled(&mut ctx.shutdown), if idle => {
+                            debug!("closing idle connection on shutdown");
+                            return Err(io::Error::from(ErrorKind::ConnectionAborted).into());
+                        }
+                    };
                     if n == 0 {
                         debug!("incoming connection closed");
                         return Err(io::Error::from(ErrorKind::ConnectionReset).into());
                     }
-                    timeout = READ_TIMEOUT;
+                    timeout = self.cfg.read_timeout;
                 }
             }
         }
@@ -326,8 +676,24 @@ where
     async fn send_status(&mut self, status: StatusCode) -> ConnectionResult {
         debug_assert!(status.is_client_error() || status.is_server_error());
         self.wbuf.clear();
-        write!(self.wbuf, "{} {}\r\n", Version::Icap10.as_str(), status)?;
-        write!(self.wbuf, "ISTag: {}\r\n", DEFAULT_IS_TAG)?;
+        match status {
+            StatusCode::NOT_FOUND => write!(
+                self.wbuf,
+                "{} {} Service not found\r\n",
+                Version::Icap10.as_str(),
+                status.as_str()
+            )?,
+            StatusCode::SERVICE_UNAVAILABLE => write!(
+                self.wbuf,
+                "{} {} Service Overloaded\r\n",
+                Version::Icap10.as_str(),
+                status.as_str()
+            )?,
+            _ => write!(self.wbuf, "{} {}\r\n", Version::Icap10.as_str(), status)?,
+        }
+        self.wbuf.extend_from_slice(b"ISTag: ");
+        self.wbuf.extend_from_slice(self.is_tag.as_bytes());
+        self.wbuf.extend_from_slice(b"\r\n");
         write!(self.wbuf, "Connection: close\r\n")?;
         write!(self.wbuf, "Encapsulated: null-body=0\r\n")?;
         write!(self.wbuf, "\r\n")?;
@@ -347,10 +713,11 @@ where
             let slc = unsafe { slice::from_raw_parts_mut(chunk.as_mut_ptr(), chunk.len()) };
             debug_assert!(slc.len() >= missing_bytes);
             trace!("reading up to {} bytes", slc.len());
-            let n = match tokio::time::timeout(READ_TIMEOUT, self.sock.read(slc)).await {
+            let timeout = self.cfg.read_timeout;
+            let n = match tokio::time::timeout(timeout, self.sock.read(slc)).await {
                 Ok(res) => res?,
                 Err(_) => {
-                    info!("socket read timeout {:?}", READ_TIMEOUT);
+                    info!("socket read timeout {:?}", timeout);
                     return Ok(ProcessingDecision::Shutdown);
                 }
             };
@@ -366,62 +733,172 @@ where
     }
 
     #[instrument(skip(self, ctx), err)]
-    async fn recv_preview_zero_chunk(&mut self, mut ctx: ReqCtxBox) -> ConnectionResult {
-        let body_buf_offset = ctx.icap_req.parsed_len + ctx.body_offset;
-        debug_assert!(ctx.rbuf.len() >= body_buf_offset);
+    async fn recv_body(&mut self, ctx: &mut ReqCtx) -> Result<(), ConnectionError> {
         trace!(
-            body_buf_offset = body_buf_offset,
+            body_buf_offset = ctx.body_buf_offset(),
             "calculated body buffer offset"
         );
+        let last_chunk = loop {
+            if let Some(hdr) = self.recv_body_part(ctx).await? {
+                break Some(hdr);
+            }
+            if ctx.preview.is_none() && ctx.body.len() >= self.cfg.body_buf_limit {
+                break None;
+            }
+        };
+        // with 'Preview' only the preview part of the body has been received,
+        // unless the client indicated with 'ieof' that the preview holds the entire body
+        ctx.body_complete = match last_chunk {
+            Some(hdr) => ctx.preview.is_none() || hdr.ieof,
+            None => false,
+        };
+        trace!(
+            len = ctx.body.len(),
+            complete = ctx.body_complete,
+            "received body"
+        );
+        Ok(())
+    }
 
+    #[instrument(skip(self, ctx), err)]
+    async fn recv_full_body(&mut self, ctx: &mut ReqCtx) -> Result<(), ConnectionError> {
+        if ctx.is_preview() {
+            self.send_continue(ctx).await?;
+        }
+        while self.recv_body_part(ctx).await?.is_none() {}
+        ctx.body_complete = true;
+        trace!(len = ctx.body.len(), "received full body");
+        Ok(())
+    }
+
+    /// Receives and discards the rest of the body
+    #[instrument(skip(self, ctx), err)]
+    async fn drain_body(&mut self, ctx: &mut ReqCtx) -> Result<(), ConnectionError> {
+        while self.recv_body_part(ctx).await?.is_none() {
+            ctx.body.clear();
+        }
+        ctx.body.clear();
+        ctx.body_complete = true;
+        trace!("drained body");
+        Ok(())
+    }
+
+    async fn send_continue(&mut self, ctx: &mut ReqCtx) -> Result<(), ConnectionError> {
+        trace!("sending 100 Continue");
+        self.wbuf.clear();
+        write!(
+            self.wbuf,
+            "{} {}\r\n\r\n",
+            Version::Icap10,
+            StatusCode::CONTINUE
+        )?;
+        self.sock.write_all(&self.wbuf).await?;
+        ctx.continue_sent = true;
+        Ok(())
+    }
+
+    async fn body_error(&mut self, e: ConnectionError) -> ConnectionResult {
+        match e {
+            ConnectionError::Decoder(e) => {
+                error!(err = %e, "failed to decode body");
+                self.send_status(StatusCode::BAD_REQUEST).await
+            }
+            e => {
+                debug!(err = %e, "failed to receive body");
+                Ok(ProcessingDecision::Shutdown)
+            }
+        }
+    }
+
+    /// Decodes the next part of the chunked body into `ctx.body`,
+    /// receiving more bytes from the socket as needed.
+    ///
+    /// Returns the last-chunk header once it has been decoded.
+    async fn recv_body_part(
+        &mut self,
+        ctx: &mut ReqCtx,
+    ) -> Result<Option<ChunkHdr>, ConnectionError> {
         loop {
-            let body_slc = &ctx.rbuf[body_buf_offset..];
+            let off = ctx.body_buf_offset();
+            debug_assert!(ctx.rbuf.len() >= off);
+            let avail = ctx.rbuf.len() - off;
 
-            let chunk_hdr = match decode_chunk_header(body_slc) {
-                Ok(None) => {
-                    trace!("partial chunk header");
-                    let n = self.recv(&mut ctx.rbuf, READ_TIMEOUT).await?;
-                    if n == 0 {
-                        debug!("incoming connection closed at preview chunk");
-                        return Ok(ProcessingDecision::Shutdown);
+            if ctx.chunk_left > 0 {
+                if avail > 0 {
+                    let n = avail.min(ctx.chunk_left);
+                    ctx.body.extend_from_slice(&ctx.rbuf[off..(off + n)]);
+                    ctx.consume_body_bytes(n);
+                    ctx.chunk_left -= n;
+                    ctx.chunk_crlf = ctx.chunk_left == 0;
+                    return Ok(None);
+                }
+            } else if ctx.chunk_crlf {
+                if avail >= 2 {
+                    let crlf_slc = &ctx.rbuf[off..(off + 2)];
+                    if crlf_slc != b"\r\n" {
+                        error!(crlf_slc = ?crlf_slc, "failed to parse chunk data final CRLF");
+                        return Err(DecoderError::BadChunkData.into());
                     }
+                    ctx.consume_body_bytes(2);
+                    ctx.chunk_crlf = false;
                     continue;
                 }
-                Ok(Some(hdr)) => hdr,
-                Err(e) => {
-                    error!(err = %e, "failed to decode chunk header");
-                    return self.send_status(StatusCode::BAD_REQUEST).await;
+            } else if let Some(hdr) = decode_chunk_header(&ctx.rbuf[off..])? {
+                trace!(chunk_hdr = ?hdr, "decoded chunk header");
+                if hdr.chunk_len != 0 {
+                    ctx.consume_body_bytes(hdr.line_len);
+                    ctx.chunk_left = hdr.chunk_len;
+                    continue;
                 }
-            };
-
-            if chunk_hdr.chunk_len != 0 {
-                error!(chunk_hdr = ?chunk_hdr, "unexpected chunk length");
-                return self.send_status(StatusCode::BAD_REQUEST).await;
-            }
-
-            if body_slc.len() < chunk_hdr.line_len + 2 {
-                trace!("missing final CRLF");
-                let n = self.recv(&mut ctx.rbuf, READ_TIMEOUT).await?;
-                if n == 0 {
-                    debug!("incoming connection closed at preview final CRLF");
-                    return Ok(ProcessingDecision::Shutdown);
+                // the last-chunk is followed by the HTTP trailer part, usually empty
+                let mut http_trailer = HeaderIndicesList::default();
+                let trailer_slc = &ctx.rbuf[(off + hdr.line_len)..];
+                if let Some(len) = decode_trailer(trailer_slc, &mut http_trailer, ctx.max_headers)?
+                {
+                    if !http_trailer.vec.is_empty() {
+                        debug!(n = http_trailer.vec.len(), "skipped HTTP trailer fields");
+                    }
+                    ctx.consume_body_bytes(hdr.line_len + len);
+                    if ctx.is_trailer_next(hdr.ieof) {
+                        self.recv_icap_trailer(ctx).await?;
+                    }
+                    return Ok(Some(hdr));
                 }
-                continue;
             }
 
-            let crlf_slc = &body_slc[chunk_hdr.line_len..(chunk_hdr.line_len + 2)];
-            if crlf_slc != b"\r\n" {
-                error!(crlf_cls = ?crlf_slc, "failed to parse chunk header final CRLF");
-                return self.send_status(StatusCode::BAD_REQUEST).await;
+            ctx.compact_body_bytes();
+            let n = self.recv(&mut ctx.rbuf, self.cfg.read_timeout).await?;
+            if n == 0 {
+                debug!("incoming connection closed while receiving body");
+                return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
             }
+        }
+    }
 
-            break Ok(ProcessingDecision::Continue(ctx));
+    /// Receives the ICAP trailer following the body
+    async fn recv_icap_trailer(&mut self, ctx: &mut ReqCtx) -> Result<(), ConnectionError> {
+        loop {
+            let off = ctx.body_buf_offset();
+            if let Some(len) = decode_trailer(&ctx.rbuf[off..], &mut ctx.trailer, ctx.max_headers)?
+            {
+                ctx.trailer_buf
+                    .extend_from_slice(&ctx.rbuf[off..(off + len)]);
+                ctx.consume_body_bytes(len);
+                trace!(n = ctx.trailer.vec.len(), "received ICAP trailer");
+                return Ok(());
+            }
+            ctx.compact_body_bytes();
+            let n = self.recv(&mut ctx.rbuf, self.cfg.read_timeout).await?;
+            if n == 0 {
+                debug!("incoming connection closed while receiving trailer");
+                return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+            }
         }
     }
 
     async fn recv(&mut self, rbuf: &mut BytesMut, timeout: Duration) -> io::Result<usize> {
-        if rbuf.capacity() <= 1024 {
-            rbuf.reserve(RBUF_CAP);
+        if rbuf.capacity() - rbuf.len() <= 1024 {
+            rbuf.reserve(self.cfg.rbuf_capacity);
         }
         let chunk = rbuf.chunk_mut();
         let slc = unsafe { slice::from_raw_parts_mut(chunk.as_mut_ptr(), chunk.len()) };
@@ -439,11 +916,1036 @@ where
     }
 }
 
+/// Encodes the request (REQMOD) or response (RESPMOD) line and headers of the adapted
+/// HTTP message into `ctx.http_buf`, without the final CRLF.
+///
+/// The request line uses the method and URI set by the handler, if any.
+///
+/// Headers removed by the handler and those named in `skip` are omitted,
+/// the latter also when appended by the handler.
+/// Returns the encapsulated entity prefix: "req" or "res".
+fn encode_http_hdr(ctx: &mut ReqCtx, skip: &[&str]) -> Result<&'static str, fmt::Error> {
+    let mut orig_skip = skip.to_vec();
+    orig_skip.extend_from_slice(&ctx.out_http_removed);
+
+    let ee = match ctx.icap_req.method {
+        Method::ReqMod => {
+            write!(
+                ctx.http_buf,
+                "{} {} {:?}\r\n",
+                ctx.out_http_method.as_ref().unwrap_or(&ctx.http_req.method),
+                ctx.out_http_uri.as_ref().unwrap_or(&ctx.http_req.uri),
+                ctx.http_req.version
+            )?;
+            ctx.http_req.headers.encode_adapted(
+                &ctx.rbuf,
+                &mut ctx.http_buf,
+                &orig_skip,
+                &ctx.out_http_replaced,
+            );
+            "req"
+        }
+        Method::RespMod => {
+            write!(
+                ctx.http_buf,
+                "{:?} {}\r\n",
+                ctx.http_res.version, ctx.http_res.status
+            )?;
+            ctx.http_res.headers.encode_adapted(
+                &ctx.rbuf,
+                &mut ctx.http_buf,
+                &orig_skip,
+                &ctx.out_http_replaced,
+            );
+            "res"
+        }
+        _ => panic!("should not get here"),
+    };
+    write_headers_map_except(&mut ctx.http_buf, &ctx.out_http_headers, skip);
+    Ok(ee)
+}
+
+/// Waits until the shutdown signal turns `true`, forever without a receiver
+async fn shutdown_signaled(shutdown: &mut Option<watch::Receiver<bool>>) {
+    if let Some(rx) = shutdown {
+        if rx.wait_for(|s| *s).await.is_ok() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+/// Encodes `data` as a single data chunk, nothing if it is empty
+fn write_chunk(buf: &mut BytesMut, data: &[u8]) -> fmt::Result {
+    if !data.is_empty() {
+        write!(buf, "{:x}\r\n", data.len())?;
+        buf.extend_from_slice(data);
+        buf.extend_from_slice(b"\r\n");
+    }
+    Ok(())
+}
+
+/// Encodes `body` as a single data chunk followed by the last-chunk
+fn write_chunked_body(buf: &mut BytesMut, body: &[u8]) -> fmt::Result {
+    write_chunk(buf, body)?;
+    buf.extend_from_slice(b"0\r\n\r\n");
+    Ok(())
+}
+
 fn write_headers_map(buf: &mut BytesMut, headers: &http::HeaderMap) {
+    write_headers_map_except(buf, headers, &[]);
+}
+
+/// Writes `headers` except those named in `skip`
+fn write_headers_map_except(buf: &mut BytesMut, headers: &http::HeaderMap, skip: &[&str]) {
     for (k, v) in headers.iter() {
+        if skip.iter().any(|s| s.eq_ignore_ascii_case(k.as_str())) {
+            continue;
+        }
         buf.extend_from_slice(k.as_str().as_bytes());
         buf.extend_from_slice(b": ");
         buf.extend_from_slice(v.as_bytes());
         buf.extend_from_slice(b"\r\n");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::CONN_ID,
+        server::{BlockPage, OptionsResponse, ServerCfg},
+        service::{Router, ServiceResult},
+        service_fn,
+    };
+    use std::future::Future;
+    use tokio::net::TcpListener;
+
+    async fn handle_options(ctx: ReqCtxBox) -> ServiceResult {
+        Ok(ctx)
+    }
+
+    // decides on the body received so far, echoes the ICAP trailer fields received
+    // in the response trailer
+    async fn handle_mod(mut ctx: ReqCtxBox) -> ServiceResult {
+        let trailers: Vec<_> = ctx
+            .icap_trailers()
+            .map(|h| HeaderValue::from_bytes(h.value.as_bytes()).unwrap())
+            .collect();
+        for val in trailers {
+            ctx.append_icap_trailer_val("X-Seen", val);
+        }
+        let body_len = HeaderValue::from(ctx.body().len());
+        let body = HeaderValue::from_bytes(ctx.body()).unwrap();
+        ctx.append_http_header_val("X-Body-Len", body_len);
+        ctx.append_http_header_val("X-Body", body);
+        ctx.set_decision(AppendHeaders);
+        Ok(ctx)
+    }
+
+    async fn handle_none(mut ctx: ReqCtxBox) -> ServiceResult {
+        ctx.append_http_header("X-Ignored", "1");
+        ctx.set_decision(NoAdaptation);
+        Ok(ctx)
+    }
+
+    // requires the full body before deciding like `handle_mod`
+    async fn handle_full(mut ctx: ReqCtxBox) -> ServiceResult {
+        if !ctx.is_body_complete() {
+            ctx.set_decision(NeedFullBody);
+            return Ok(ctx);
+        }
+        handle_mod(ctx).await
+    }
+
+    // streams the body upper-cased, the number of chunks is sent in the "X-Chunks" trailer
+    async fn handle_upper(mut ctx: ReqCtxBox) -> ServiceResult {
+        ctx.declare_icap_trailer("X-Chunks");
+        let (mut stream, sink) = ctx.transform_body();
+        tokio::spawn(async move {
+            let mut n = 0;
+            while let Some(chunk) = stream.next().await {
+                n += 1;
+                let chunk = Bytes::from(chunk.to_ascii_uppercase());
+                if sink.send(chunk).await.is_err() {
+                    return;
+                }
+            }
+            let mut trailers = http::HeaderMap::new();
+            trailers.insert("X-Chunks", HeaderValue::from(n));
+            sink.finish_with_trailers(trailers);
+        });
+        Ok(ctx)
+    }
+
+    // replaces the first 5 body bytes with "Hi"
+    async fn handle_prefix(mut ctx: ReqCtxBox) -> ServiceResult {
+        ctx.set_http_body_prefix("Hi", 5);
+        ctx.set_decision(AppendHeaders);
+        Ok(ctx)
+    }
+
+    async fn roundtrip(req: &[u8]) -> String {
+        roundtrip_fn(handle_mod, req).await
+    }
+
+    async fn roundtrip_fn<H, F>(handle: H, req: &[u8]) -> String
+    where
+        H: Clone + Send + FnMut(ReqCtxBox) -> F + 'static,
+        F: Future<Output = ServiceResult> + Send + 'static,
+    {
+        let svc = service_fn(
+            ServerCfg::builder().build(),
+            handle_options,
+            handle.clone(),
+            handle,
+        );
+        roundtrip_svc(svc, req).await
+    }
+
+    async fn roundtrip_svc<S>(svc: S, req: &[u8]) -> String
+    where
+        S: IcapService + Send + 'static,
+        <S as IcapService>::OPF: Send,
+        <S as IcapService>::RQF: Send,
+        <S as IcapService>::RSF: Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let srv = tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            Connection::new(CONN_ID.next(), sock, svc).process().await;
+        });
+
+        let mut cli = TcpStream::connect(addr).await.unwrap();
+        cli.write_all(req).await.unwrap();
+        cli.shutdown().await.unwrap();
+        let mut res = Vec::new();
+        cli.read_to_end(&mut res).await.unwrap();
+        srv.await.unwrap();
+        String::from_utf8(res).unwrap()
+    }
+
+    fn reqmod(path: &str, icap_hdrs: &str, body: &str) -> Vec<u8> {
+        let http_hdr = "POST /upload HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            Content-Length: 11\r\n\
+            \r\n";
+        format!(
+            "REQMOD icap://127.0.0.1{} ICAP/1.0\r\n\
+            Host: 127.0.0.1\r\n\
+            {}\
+            Encapsulated: req-hdr=0, req-body={}\r\n\
+            \r\n\
+            {}{}",
+            path,
+            icap_hdrs,
+            http_hdr.len(),
+            http_hdr,
+            body
+        )
+        .into_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_full_body_without_preview() {
+        let req = reqmod(
+            "/svc",
+            "Allow: 204, 206\r\n",
+            "5\r\nhello\r\n6; ext=val\r\n world\r\n0\r\n\r\n",
+        );
+        let res = roundtrip(&req).await;
+        assert!(res.starts_with("ICAP/1.0 206 Partial Content\r\n"));
+        assert!(res.contains("\r\nx-body-len: 11\r\n"));
+        assert!(res.contains("\r\nx-body: hello world\r\n"));
+        assert!(res.ends_with("\r\n\r\n0; use-original-body=0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_preview_zero() {
+        let req = reqmod("/svc", "Allow: 204, 206\r\nPreview: 0\r\n", "0\r\n\r\n");
+        let res = roundtrip(&req).await;
+        assert!(res.starts_with("ICAP/1.0 206 Partial Content\r\n"));
+        assert!(res.contains("\r\nx-body-len: 0\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_preview_decision() {
+        let req = reqmod(
+            "/svc",
+            "Allow: 204, 206\r\nPreview: 4\r\n",
+            "4\r\nhell\r\n0\r\n\r\n",
+        );
+        let res = roundtrip(&req).await;
+        assert!(res.starts_with("ICAP/1.0 206 Partial Content\r\n"));
+        assert!(res.contains("\r\nx-body: hell\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_preview_continue() {
+        let req = reqmod(
+            "/svc",
+            "Allow: 204, 206\r\nPreview: 4\r\n",
+            "4\r\nhell\r\n0\r\n\r\n7\r\no world\r\n0\r\n\r\n",
+        );
+        let res = roundtrip_fn(handle_full, &req).await;
+        assert!(res.starts_with("ICAP/1.0 100 Continue\r\n\r\nICAP/1.0 206 Partial Content\r\n"));
+        assert!(res.contains("\r\nx-body: hello world\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_preview_ieof() {
+        let req = reqmod(
+            "/svc",
+            "Allow: 204, 206\r\nPreview: 10\r\n",
+            "5\r\nhello\r\n0; ieof\r\n\r\n",
+        );
+        let res = roundtrip_fn(handle_full, &req).await;
+        assert!(res.starts_with("ICAP/1.0 206 Partial Content\r\n"));
+        assert!(res.contains("\r\nx-body: hello\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_replace_body() {
+        async fn replace(mut ctx: ReqCtxBox) -> ServiceResult {
+            ctx.set_http_body("replaced");
+            ctx.set_decision(ReplaceBody);
+            Ok(ctx)
+        }
+        let req = reqmod(
+            "/svc",
+            "Allow: 204, 206\r\n",
+            "b\r\nhello world\r\n0\r\n\r\n",
+        );
+        let res = roundtrip_fn(replace, &req).await;
+        let http_hdr = "POST /upload HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            Content-Length: 8\r\n\
+            \r\n";
+        assert!(res.starts_with("ICAP/1.0 200 OK\r\n"));
+        assert!(res.contains(&format!(
+            "\r\nEncapsulated: req-hdr=0, req-body={}\r\n",
+            http_hdr.len()
+        )));
+        assert!(res.ends_with(&format!("\r\n\r\n{}8\r\nreplaced\r\n0\r\n\r\n", http_hdr)));
+    }
+
+    #[tokio::test]
+    async fn test_replace_body_framing_headers() {
+        async fn replace(mut ctx: ReqCtxBox) -> ServiceResult {
+            ctx.set_http_body("replaced");
+            ctx.append_http_header("Content-Length", "999");
+            ctx.append_http_header("Transfer-Encoding", "chunked");
+            ctx.append_http_header("X-New", "1");
+            ctx.set_decision(ReplaceBody);
+            Ok(ctx)
+        }
+        let req = reqmod("/svc", "Allow: 204\r\n", "b\r\nhello world\r\n0\r\n\r\n");
+        let res = roundtrip_fn(replace, &req).await;
+        assert!(res.ends_with(
+            "\r\n\r\nPOST /upload HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            x-new: 1\r\n\
+            Content-Length: 8\r\n\
+            \r\n\
+            8\r\nreplaced\r\n0\r\n\r\n"
+        ));
+        assert!(!res.to_ascii_lowercase().contains("transfer-encoding"));
+        assert!(!res.contains("999"));
+    }
+
+    #[tokio::test]
+    async fn test_transform_body() {
+        let req = reqmod(
+            "/svc",
+            "Allow: 204, 206\r\n",
+            "5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+        );
+        let res = roundtrip_fn(handle_upper, &req).await;
+        assert!(res.starts_with("ICAP/1.0 200 OK\r\n"));
+        assert!(!res.contains("Content-Length"));
+        assert!(res.ends_with("\r\n\r\nb\r\nHELLO WORLD\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_transform_body_preview() {
+        let req = reqmod(
+            "/svc",
+            "Allow: 204, 206\r\nPreview: 4\r\n",
+            "4\r\nhell\r\n0\r\n\r\n7\r\no world\r\n0\r\n\r\n",
+        );
+        let res = roundtrip_fn(handle_upper, &req).await;
+        assert!(res.starts_with("ICAP/1.0 100 Continue\r\n\r\nICAP/1.0 200 OK\r\n"));
+        assert!(res.ends_with("\r\n\r\n4\r\nHELL\r\n7\r\nO WORLD\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_large_body_drained() {
+        let data = "x".repeat(0x30000);
+        let req = reqmod(
+            "/svc",
+            "Allow: 204, 206\r\n",
+            &format!("{:x}\r\n{}\r\n0\r\n\r\n", data.len(), data),
+        );
+        let res = roundtrip(&req).await;
+        assert!(res.starts_with("ICAP/1.0 206 Partial Content\r\n"));
+        assert!(res.ends_with("\r\n\r\n0; use-original-body=0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_no_allow_206() {
+        let req = reqmod("/svc", "", "b\r\nhello world\r\n0\r\n\r\n");
+        let res = roundtrip(&req).await;
+        assert!(res.starts_with("ICAP/1.0 200 OK\r\n"));
+        assert!(res.contains("\r\nx-body: hello world\r\n"));
+        assert!(res.ends_with("\r\n\r\nb\r\nhello world\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_no_allow_206_preview() {
+        let req = reqmod(
+            "/svc",
+            "Allow: 204\r\nPreview: 4\r\n",
+            "4\r\nhell\r\n0\r\n\r\n7\r\no world\r\n0\r\n\r\n",
+        );
+        let res = roundtrip(&req).await;
+        assert!(res.starts_with("ICAP/1.0 100 Continue\r\n\r\nICAP/1.0 200 OK\r\n"));
+        assert!(res.contains("\r\nx-body: hell\r\n"));
+        assert!(res.ends_with("\r\n\r\n4\r\nhell\r\n7\r\no world\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_no_allow_206_large_body_streamed() {
+        let cfg = ServerCfg::builder().body_buf_limit(1024).build();
+        let svc = service_fn(cfg, handle_options, handle_prefix, handle_prefix);
+        let data = "x".repeat(0x8000);
+        let req = reqmod(
+            "/svc",
+            "",
+            &format!("{:x}\r\n{}\r\n0\r\n\r\n", data.len(), data),
+        );
+        let res = roundtrip_svc(svc, &req).await;
+        assert!(res.starts_with("ICAP/1.0 200 OK\r\n"));
+        let body = res.split_once("\r\n\r\n2\r\nHi\r\n").unwrap().1;
+        let mut streamed = 0;
+        let mut chunks = 0;
+        for chunk in body.split("\r\n").collect::<Vec<_>>().chunks(2) {
+            let len = usize::from_str_radix(chunk[0], 16).unwrap();
+            if len == 0 {
+                break;
+            }
+            assert_eq!(chunk[1].len(), len);
+            streamed += len;
+            chunks += 1;
+        }
+        assert_eq!(streamed, data.len() - 5);
+        assert!(chunks > 1, "{} chunks", chunks);
+    }
+
+    #[tokio::test]
+    async fn test_no_adaptation() {
+        let req = reqmod("/svc", "Allow: 204\r\n", "b\r\nhello world\r\n0\r\n\r\n");
+        let res = roundtrip_fn(handle_none, &req).await;
+        assert!(res.starts_with("ICAP/1.0 204 No Content\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_no_allow_204() {
+        let req = reqmod("/svc", "", "b\r\nhello world\r\n0\r\n\r\n");
+        let res = roundtrip_fn(handle_none, &req).await;
+        let http_hdr = "POST /upload HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            Content-Length: 11\r\n\
+            \r\n";
+        assert!(res.starts_with("ICAP/1.0 200 OK\r\n"));
+        assert!(res.ends_with(&format!(
+            "\r\n\r\n{}b\r\nhello world\r\n0\r\n\r\n",
+            http_hdr
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_trailers() {
+        let req = reqmod(
+            "/svc",
+            "Allow: 204, 206, trailers\r\nTrailer: X-Client-Status\r\n",
+            "b\r\nhello world\r\n0\r\nX-Http: 1\r\n\r\nX-Client-Status: ok\r\n\r\n",
+        );
+        let res = roundtrip(&req).await;
+        assert!(res.starts_with("ICAP/1.0 206 Partial Content\r\n"));
+        assert!(res.contains("\r\nallow: trailers\r\ntrailer: x-seen\r\n"));
+        assert!(res.ends_with("\r\n\r\n0; use-original-body=0\r\n\r\nx-seen: ok\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_trailers_allow_merged() {
+        async fn handle(mut ctx: ReqCtxBox) -> ServiceResult {
+            ctx.append_icap_res_header("Allow", "204");
+            handle_mod(ctx).await
+        }
+        let req = reqmod(
+            "/svc",
+            "Allow: 204, 206, trailers\r\nTrailer: X-Client-Status\r\n",
+            "b\r\nhello world\r\n0\r\n\r\nX-Client-Status: ok\r\n\r\n",
+        );
+        let res = roundtrip_fn(handle, &req).await;
+        assert!(res.contains("\r\nallow: 204, trailers\r\n"), "{}", res);
+        assert_eq!(res.matches("\r\nallow: ").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_preview_over_body_buf_limit() {
+        let cfg = ServerCfg::builder().body_buf_limit(1024).build();
+        let svc = service_fn(cfg, handle_options, handle_mod, handle_mod);
+        let req = reqmod("/svc", "Allow: 204\r\nPreview: 1025\r\n", "0\r\n\r\n");
+        let res = roundtrip_svc(svc, &req).await;
+        assert!(res.starts_with("ICAP/1.0 400 Bad Request\r\n"), "{}", res);
+    }
+
+    #[tokio::test]
+    async fn test_trailer_not_allowed() {
+        let req = reqmod(
+            "/svc",
+            "Allow: 204, 206\r\nTrailer: X-Client-Status\r\n",
+            "b\r\nhello world\r\n0\r\n\r\nX-Client-Status: ok\r\n\r\n",
+        );
+        let res = roundtrip(&req).await;
+        assert!(res.starts_with("ICAP/1.0 400 Bad Request\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_transform_body_trailer() {
+        let req = reqmod(
+            "/svc",
+            "Allow: 204, 206, trailers\r\n",
+            "b\r\nhello world\r\n0\r\n\r\n",
+        );
+        let res = roundtrip_fn(handle_upper, &req).await;
+        assert!(res.starts_with("ICAP/1.0 200 OK\r\n"));
+        assert!(res.contains("\r\ntrailer: X-Chunks\r\n"));
+        assert!(res.ends_with("\r\n\r\nb\r\nHELLO WORLD\r\n0\r\n\r\nx-chunks: 1\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_body_prefix() {
+        let req = reqmod(
+            "/svc",
+            "Allow: 204, 206\r\nPreview: 4\r\n",
+            "4\r\nhell\r\n0\r\n\r\n",
+        );
+        let res = roundtrip_fn(handle_prefix, &req).await;
+        assert!(res.starts_with("ICAP/1.0 206 Partial Content\r\n"));
+        assert!(res.contains("\r\nContent-Length: 8\r\n"));
+        assert!(res.ends_with("\r\n\r\n2\r\nHi\r\n0; use-original-body=5\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_body_prefix_no_allow_206() {
+        let req = reqmod("/svc", "", "b\r\nhello world\r\n0\r\n\r\n");
+        let res = roundtrip_fn(handle_prefix, &req).await;
+        assert!(res.starts_with("ICAP/1.0 200 OK\r\n"));
+        assert!(res.contains("\r\nContent-Length: 8\r\n"));
+        assert!(res.ends_with("\r\n\r\n2\r\nHi\r\n6\r\n world\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_header_rewrite() {
+        async fn rewrite(mut ctx: ReqCtxBox) -> ServiceResult {
+            ctx.set_http_header("Host", "mirror.example.com");
+            ctx.remove_http_header("Content-Length");
+            ctx.append_http_header("X-New", "1");
+            ctx.set_decision(AppendHeaders);
+            Ok(ctx)
+        }
+        let req = reqmod("/svc", "Allow: 204, 206\r\nPreview: 0\r\n", "0\r\n\r\n");
+        let res = roundtrip_fn(rewrite, &req).await;
+        assert!(res.starts_with("ICAP/1.0 206 Partial Content\r\n"));
+        assert!(res.contains(
+            "\r\n\r\nPOST /upload HTTP/1.1\r\n\
+            Host: mirror.example.com\r\n\
+            x-new: 1\r\n\
+            \r\n0; use-original-body=0\r\n\r\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_request_line_rewrite() {
+        async fn rewrite(mut ctx: ReqCtxBox) -> ServiceResult {
+            ctx.set_http_method(http::Method::PUT);
+            ctx.set_http_uri(http::Uri::from_static("/upload?safe=active"));
+            ctx.set_decision(AppendHeaders);
+            Ok(ctx)
+        }
+        let req = reqmod("/svc", "Allow: 204, 206\r\nPreview: 0\r\n", "0\r\n\r\n");
+        let res = roundtrip_fn(rewrite, &req).await;
+        assert!(res.starts_with("ICAP/1.0 206 Partial Content\r\n"));
+        assert!(res.contains("\r\n\r\nPUT /upload?safe=active HTTP/1.1\r\nHost: example.com\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_block_page() {
+        async fn block(mut ctx: ReqCtxBox) -> ServiceResult {
+            ctx.set_block_page(
+                &BlockPage::new("<p>{url} {category} {client_ip}</p>"),
+                "gambling",
+            );
+            Ok(ctx)
+        }
+        let req = reqmod(
+            "/svc",
+            "Allow: 204\r\nX-Client-IP: 10.0.0.1\r\n",
+            "b\r\nhello world\r\n0\r\n\r\n",
+        );
+        let res = roundtrip_fn(block, &req).await;
+        let body = "<p>http://example.com/upload gambling 10.0.0.1</p>";
+        let http_hdr = format!(
+            "HTTP/1.1 403 Forbidden\r\n\
+            content-type: text/html; charset=utf-8\r\n\
+            Content-Length: {}\r\n\
+            \r\n",
+            body.len()
+        );
+        assert!(res.starts_with("ICAP/1.0 200 OK\r\n"));
+        assert!(res.contains(&format!(
+            "\r\nEncapsulated: res-hdr=0, res-body={}\r\n",
+            http_hdr.len()
+        )));
+        assert!(res.ends_with(&format!(
+            "\r\n\r\n{}{:x}\r\n{}\r\n0\r\n\r\n",
+            http_hdr,
+            body.len(),
+            body
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_router() {
+        let cfg = ServerCfg::builder().build();
+        let router = Router::new(cfg.clone()).route(
+            "/a",
+            service_fn(cfg, handle_options, handle_mod, handle_mod),
+        );
+
+        let req = b"OPTIONS icap://127.0.0.1/a ICAP/1.0\r\nHost: 127.0.0.1\r\n\r\n";
+        let res = roundtrip_svc(router.clone(), req).await;
+        assert!(res.starts_with("ICAP/1.0 200 OK\r\n"));
+
+        let req = reqmod("/a", "Allow: 204\r\n", "b\r\nhello world\r\n0\r\n\r\n");
+        let res = roundtrip_svc(router.clone(), &req).await;
+        assert!(res.starts_with("ICAP/1.0 200 OK\r\n"));
+        assert!(res.contains("\r\nx-body: hello world\r\n"));
+
+        let req = reqmod("/b", "Allow: 204\r\n", "b\r\nhello world\r\n0\r\n\r\n");
+        let res = roundtrip_svc(router, &req).await;
+        assert!(res.starts_with("ICAP/1.0 404 Service not found\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_error_is_tag() {
+        async fn fail(_: Box<ReqCtx>) -> ServiceResult {
+            Err(500.into())
+        }
+        let opts = OptionsResponse::builder().is_tag("tag-1").build().unwrap();
+        let cfg = ServerCfg::builder().options(opts).build();
+        let router = Router::new(ServerCfg::builder().build())
+            .route("/a", service_fn(cfg, handle_options, fail, fail));
+
+        let req = reqmod("/a", "Allow: 204\r\n", "0\r\n\r\n");
+        let res = roundtrip_svc(router.clone(), &req).await;
+        assert!(
+            res.starts_with("ICAP/1.0 500 Internal Server Error\r\n"),
+            "{}",
+            res
+        );
+        assert!(res.contains("\r\nISTag: \"tag-1\"\r\n"), "{}", res);
+
+        let req = reqmod("/b", "Allow: 204\r\n", "0\r\n\r\n");
+        let res = roundtrip_svc(router, &req).await;
+        assert!(res.starts_with("ICAP/1.0 404 Service not found\r\n"));
+        assert!(!res.contains("tag-1"), "{}", res);
+    }
+
+    #[tokio::test]
+    async fn test_options() {
+        let opts = OptionsResponse::builder()
+            .is_tag("tag-1")
+            .options_ttl(Duration::from_secs(60))
+            .allow_206(false)
+            .build()
+            .unwrap();
+        let svc = service_fn(
+            ServerCfg::builder().options(opts).build(),
+            handle_options,
+            handle_mod,
+            handle_mod,
+        );
+        let req = b"OPTIONS icap://127.0.0.1/svc ICAP/1.0\r\n\
+            Host: 127.0.0.1\r\n\
+            Allow: trailers\r\n\
+            \r\n";
+        let res = roundtrip_svc(svc, req).await;
+        assert!(res.starts_with("ICAP/1.0 200 OK\r\n"));
+        assert!(res.contains("\r\nmethods: REQMOD, RESPMOD\r\n"));
+        assert!(res.contains("\r\nistag: \"tag-1\"\r\n"));
+        assert!(res.contains("\r\noptions-ttl: 60\r\n"));
+        assert!(res.contains("\r\nallow: 204, trailers\r\n"));
+        assert!(res.contains("\r\ndate: "));
+    }
+
+    #[tokio::test]
+    async fn test_options_allow_206() {
+        for (client_allow, advertised) in [("", "204"), ("Allow: 206\r\n", "204, 206")] {
+            let req = format!(
+                "OPTIONS icap://127.0.0.1/svc ICAP/1.0\r\nHost: 127.0.0.1\r\n{}\r\n",
+                client_allow
+            );
+            let res = roundtrip(req.as_bytes()).await;
+            let hdr = format!("\r\nallow: {}\r\n", advertised);
+            assert!(res.contains(&hdr), "{}", res);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_options_max_connections() {
+        let req = b"OPTIONS icap://127.0.0.1/svc ICAP/1.0\r\nHost: 127.0.0.1\r\n\r\n";
+        let options = |max: Option<u32>| {
+            let builder = OptionsResponse::builder();
+            match max {
+                Some(max) => builder.max_connections(max),
+                None => builder,
+            }
+            .build()
+            .unwrap()
+        };
+        for (svc_max, advertised) in [(None, 10), (Some(5), 5), (Some(20), 10)] {
+            let cfg = ServerCfg::builder()
+                .options(options(svc_max))
+                .max_connections(10)
+                .build();
+            let svc = service_fn(cfg, handle_options, handle_mod, handle_mod);
+            let res = roundtrip_svc(svc, req).await;
+            let hdr = format!("\r\nmax-connections: {}\r\n", advertised);
+            assert!(res.contains(&hdr), "{}", res);
+        }
+
+        // the limit of the server routing the request applies, not the one of the service
+        let cfg = ServerCfg::builder().max_connections(50).build();
+        let router = Router::new(ServerCfg::builder().max_connections(10).build()).route(
+            "/svc",
+            service_fn(cfg, handle_options, handle_mod, handle_mod),
+        );
+        let res = roundtrip_svc(router, req).await;
+        assert!(res.contains("\r\nmax-connections: 10\r\n"), "{}", res);
+    }
+
+    #[tokio::test]
+    async fn test_pipelining() {
+        async fn handle(ctx: ReqCtxBox) -> ServiceResult {
+            match ctx.icap_req().uri.path() {
+                "/none" => handle_none(ctx).await,
+                _ => handle_upper(ctx).await,
+            }
+        }
+        let mut req = reqmod("/upper", "", "b\r\nhello world\r\n0\r\n\r\n");
+        req.extend(b"OPTIONS icap://127.0.0.1/svc ICAP/1.0\r\nHost: 127.0.0.1\r\n\r\n");
+        req.extend(reqmod(
+            "/none",
+            "Allow: 204\r\n",
+            "b\r\nhello world\r\n0\r\n\r\n",
+        ));
+        let res = roundtrip_fn(handle, &req).await;
+        let statuses: Vec<_> = res
+            .match_indices("ICAP/1.0 ")
+            .map(|(i, _)| &res[i..(i + 12)])
+            .collect();
+        assert_eq!(statuses, ["ICAP/1.0 200", "ICAP/1.0 200", "ICAP/1.0 204"]);
+        assert!(res.contains("\r\nmethods: REQMOD, RESPMOD\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_pipelining_preview() {
+        async fn handle(ctx: ReqCtxBox) -> ServiceResult {
+            match ctx.icap_req().uri.path() {
+                "/full" => handle_full(ctx).await,
+                _ => handle_mod(ctx).await,
+            }
+        }
+        let mut req = reqmod(
+            "/full",
+            "Allow: 204, 206\r\nPreview: 4\r\n",
+            "4\r\nhell\r\n0\r\n\r\n7\r\no world\r\n0\r\n\r\n",
+        );
+        req.extend(reqmod(
+            "/svc",
+            "Allow: 204, 206, trailers\r\nTrailer: X-Client-Status\r\n",
+            "5\r\nhello\r\n0\r\n\r\nX-Client-Status: ok\r\n\r\n",
+        ));
+        req.extend(reqmod(
+            "/svc",
+            "Allow: 204, 206\r\nPreview: 0\r\n",
+            "0\r\n\r\n",
+        ));
+        let res = roundtrip_fn(handle, &req).await;
+        assert!(res.starts_with("ICAP/1.0 100 Continue\r\n\r\nICAP/1.0 206 Partial Content\r\n"));
+        assert_eq!(res.matches("ICAP/1.0 206 Partial Content\r\n").count(), 3);
+        assert!(res.contains("\r\nx-body: hello world\r\n"));
+        assert!(res.contains("\r\nx-seen: ok\r\n"));
+        assert!(res.contains("\r\nx-body-len: 0\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_close() {
+        let mut req = reqmod(
+            "/svc",
+            "Allow: 204\r\nConnection: close\r\n",
+            "b\r\nhello world\r\n0\r\n\r\n",
+        );
+        req.extend(reqmod("/svc", "Allow: 204\r\n", "0\r\n\r\n"));
+        let res = roundtrip_fn(handle_none, &req).await;
+        assert!(res.starts_with("ICAP/1.0 204 No Content\r\n"));
+        assert!(res.contains("\r\nconnection: close\r\n"));
+        assert_eq!(res.matches("ICAP/1.0 ").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_max_requests() {
+        let cfg = ServerCfg::builder().max_requests(2).build();
+        let svc = service_fn(cfg, handle_options, handle_none, handle_none);
+        let mut req = Vec::new();
+        for _ in 0..3 {
+            req.extend(reqmod("/svc", "Allow: 204\r\n", "0\r\n\r\n"));
+        }
+        let res = roundtrip_svc(svc, &req).await;
+        let conn_hdrs: Vec<_> = res
+            .lines()
+            .filter(|l| l.starts_with("connection: "))
+            .collect();
+        assert_eq!(conn_hdrs, ["connection: keep-alive", "connection: close"]);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let cfg = ServerCfg::builder()
+            .idle_timeout(Duration::from_millis(50))
+            .build();
+        let svc = service_fn(cfg, handle_options, handle_mod, handle_mod);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let srv = tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            Connection::new(CONN_ID.next(), sock, svc).process().await;
+        });
+
+        let mut cli = TcpStream::connect(addr).await.unwrap();
+        let mut res = Vec::new();
+        let n = tokio::time::timeout(Duration::from_secs(5), cli.read_to_end(&mut res))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(n, 0);
+        srv.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_headers() {
+        async fn client(mut ctx: ReqCtxBox) -> ServiceResult {
+            let client = match ctx.authenticated_groups() {
+                Ok(groups) => format!(
+                    "{:?} {:?} {:?} {:?} {:?} {:?}",
+                    ctx.client_ip().unwrap(),
+                    ctx.server_ip().unwrap(),
+                    ctx.client_username().unwrap(),
+                    ctx.authenticated_user().unwrap(),
+                    groups,
+                    ctx.subscriber_id().unwrap(),
+                ),
+                Err(e) => e.to_string(),
+            };
+            ctx.append_http_header_val("X-Client", HeaderValue::try_from(client).unwrap());
+            ctx.set_decision(AppendHeaders);
+            Ok(ctx)
+        }
+        let req = reqmod(
+            "/svc",
+            "Allow: 204, 206\r\n\
+            X-Client-IP: 10.0.0.1\r\n\
+            X-Server-IP: ::1\r\n\
+            X-Client-Username: joe\r\n\
+            X-Authenticated-User: TG9jYWw6Ly9qb2U=\r\n\
+            X-Authenticated-Groups: TG9jYWw6Ly9hZG1pbnMsIExvY2FsOi8vdXNlcnM=\r\n\
+            Preview: 0\r\n",
+            "0\r\n\r\n",
+        );
+        let res = roundtrip_fn(client, &req).await;
+        assert!(res.contains(
+            "\r\nx-client: Some(10.0.0.1) Some(::1) Some(\"joe\") Some(\"Local://joe\") \
+            Some([\"Local://admins\", \"Local://users\"]) None\r\n"
+        ));
+
+        let req = reqmod(
+            "/svc",
+            "Allow: 204, 206\r\nX-Authenticated-Groups: %%%\r\nPreview: 0\r\n",
+            "0\r\n\r\n",
+        );
+        let res = roundtrip_fn(client, &req).await;
+        assert!(res.contains("\r\nx-client: bad base64 in 'X-Authenticated-Groups' header\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_http_request_response() {
+        async fn echo_upper(mut ctx: ReqCtxBox) -> ServiceResult {
+            if !ctx.is_body_complete() {
+                ctx.set_decision(NeedFullBody);
+                return Ok(ctx);
+            }
+            assert!(ctx.to_http_response().unwrap().is_none());
+            let req = ctx.to_http_request().unwrap().unwrap();
+            let res = http::Response::builder()
+                .status(StatusCode::CREATED)
+                .header("X-Method", req.method().as_str())
+                .header("X-Host", req.headers()["host"].clone())
+                .body(req.body().to_ascii_uppercase())
+                .unwrap();
+            ctx.set_http_response(res);
+            Ok(ctx)
+        }
+        let req = reqmod(
+            "/svc",
+            "Allow: 204\r\nPreview: 4\r\n",
+            "4\r\nhell\r\n0\r\n\r\n7\r\no world\r\n0\r\n\r\n",
+        );
+        let res = roundtrip_fn(echo_upper, &req).await;
+        assert!(res.starts_with("ICAP/1.0 100 Continue\r\n\r\nICAP/1.0 200 OK\r\n"));
+        assert!(res.ends_with(
+            "HTTP/1.1 201 Created\r\n\
+            x-method: POST\r\n\
+            x-host: example.com\r\n\
+            Content-Length: 11\r\n\
+            \r\n\
+            b\r\nHELLO WORLD\r\n0\r\n\r\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_transfer_ignore() {
+        let opts = OptionsResponse::builder()
+            .transfer_ignore(&["jpg"])
+            .build()
+            .unwrap();
+        let cfg = ServerCfg::builder().options(opts).build();
+        let svc = service_fn(cfg, handle_options, handle_mod, handle_mod);
+
+        let req = |path: &str| {
+            let http_hdr = format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", path);
+            format!(
+                "REQMOD icap://127.0.0.1/svc ICAP/1.0\r\n\
+                Host: 127.0.0.1\r\n\
+                Allow: 204\r\n\
+                Encapsulated: req-hdr=0, null-body={}\r\n\
+                \r\n\
+                {}",
+                http_hdr.len(),
+                http_hdr
+            )
+        };
+
+        let res = roundtrip_svc(svc.clone(), req("/img/a.JPG?x=1.txt").as_bytes()).await;
+        assert!(res.starts_with("ICAP/1.0 204 No Content\r\n"));
+
+        let res = roundtrip_svc(svc, req("/img.jpg/a.txt").as_bytes()).await;
+        assert!(res.starts_with("ICAP/1.0 200 OK\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_transfer_ignore_preview() {
+        let opts = OptionsResponse::builder()
+            .transfer_ignore(&["zip"])
+            .build()
+            .unwrap();
+        let cfg = ServerCfg::builder().options(opts).build();
+        let svc = service_fn(cfg, handle_options, handle_mod, handle_mod);
+        let http_hdr = "GET /a.zip HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let msg = |preview: &str| {
+            format!(
+                "REQMOD icap://127.0.0.1/svc ICAP/1.0\r\n\
+                Host: 127.0.0.1\r\n\
+                Preview: 4\r\n\
+                Encapsulated: req-hdr=0, req-body={}\r\n\
+                \r\n\
+                {}{}",
+                http_hdr.len(),
+                http_hdr,
+                preview
+            )
+        };
+        // the preview is consumed, the next pipelined message is processed
+        let req = msg("4\r\nhell\r\n0\r\n\r\n") + &msg("4\r\nhell\r\n0; ieof\r\n\r\n");
+        let res = roundtrip_svc(svc, req.as_bytes()).await;
+        assert_eq!(
+            res.matches("ICAP/1.0 204 No Content\r\n").count(),
+            2,
+            "{}",
+            res
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transfer_ignore_no_allow_204() {
+        let opts = OptionsResponse::builder()
+            .transfer_ignore(&["zip"])
+            .build()
+            .unwrap();
+        let cfg = ServerCfg::builder()
+            .options(opts)
+            .body_buf_limit(1024)
+            .build();
+        let svc = service_fn(cfg, handle_options, handle_mod, handle_mod);
+        let http_hdr = "GET /a.zip HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let data = "x".repeat(0x4000);
+        let req = format!(
+            "REQMOD icap://127.0.0.1/svc ICAP/1.0\r\n\
+            Host: 127.0.0.1\r\n\
+            Encapsulated: req-hdr=0, req-body={}\r\n\
+            \r\n\
+            {}{:x}\r\n{}\r\n0\r\n\r\n",
+            http_hdr.len(),
+            http_hdr,
+            data.len(),
+            data
+        );
+        let res = roundtrip_svc(svc, req.as_bytes()).await;
+        assert!(res.starts_with("ICAP/1.0 200 OK\r\n"));
+        assert!(!res.contains("x-body"));
+        let body = res.split_once(http_hdr).unwrap().1;
+        assert_eq!(body.matches('x').count(), data.len());
+        assert!(res.ends_with("\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_max_headers() {
+        let cfg = ServerCfg::builder().max_headers(3).build();
+        let svc = service_fn(cfg, handle_options, handle_none, handle_none);
+        let req = reqmod("/svc", "Allow: 204\r\n", "0\r\n\r\n");
+        let res = roundtrip_svc(svc.clone(), &req).await;
+        assert!(res.starts_with("ICAP/1.0 204 No Content\r\n"));
+
+        let req = reqmod(
+            "/svc",
+            "Allow: 204\r\nX-Client-IP: 10.0.0.1\r\n",
+            "0\r\n\r\n",
+        );
+        let res = roundtrip_svc(svc, &req).await;
+        assert!(res.starts_with("ICAP/1.0 400 Bad Request\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_bad_chunk_data() {
+        let req = reqmod(
+            "/svc",
+            "Allow: 204, 206\r\n",
+            "5\r\nhello world\r\n0\r\n\r\n",
+        );
+        let res = roundtrip(&req).await;
+        assert!(res.starts_with("ICAP/1.0 400 Bad Request\r\n"));
+    }
+}
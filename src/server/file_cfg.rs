@@ -0,0 +1,468 @@
+//! Server configuration loaded from a TOML file
+//!
+//! ```toml
+//! [server]
+//! listen = ["127.0.0.1:1344", "[::1]:1344"]
+//! idle_timeout = "60s"  # or seconds, e.g. 60; units: ms, s, m, h
+//! read_timeout = "10s"
+//! shutdown_timeout = "30s"
+//! max_requests = 1000
+//! max_connections = 500  # advertised as Max-Connections
+//! max_headers = 128
+//! rbuf_capacity = 8192
+//! http_buf_capacity = 8192
+//! body_buf_limit = 65536
+//!
+//! [log]
+//! level = "info"
+//! filter = "icap_poc=debug"
+//!
+//! [[service]]
+//! path = "/reqmod"
+//! istag = "policy-1"
+//! methods = ["REQMOD"]
+//! preview = 1024        # or false to disable preview
+//! service = "URL filter"
+//! options_ttl = "1h"
+//! transfer_ignore = ["jpg", "png"]
+//! ```
+use crate::{
+    errors::FileCfgError,
+    server::{OptionsResponse, OptionsResponseBuilder, ServerCfg, ServerCfgBuilder},
+    Method,
+};
+use serde::{
+    de::{self, Deserializer, Visitor},
+    Deserialize,
+};
+use std::{
+    collections::HashSet, fmt, net::SocketAddr, path::Path, str::FromStr, sync::Arc, time::Duration,
+};
+use toml::Spanned;
+
+/// Configuration loaded from a TOML file
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FileCfg {
+    /// Addresses to listen on
+    pub listen: Vec<SocketAddr>,
+    /// Server-wide configuration, e.g. for a [Router](crate::service::Router)
+    pub server: Arc<ServerCfg>,
+    /// Services in order of appearance
+    pub services: Vec<FileServiceCfg>,
+    pub log: LogCfg,
+}
+
+/// Service defined in a `[[service]]` table
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FileServiceCfg {
+    /// The path of the ICAP request URI, e.g. "/reqmod"
+    pub path: String,
+    /// The server-wide configuration with the OPTIONS response of the service
+    pub cfg: Arc<ServerCfg>,
+}
+
+/// Logging settings, applied by the application to its tracing subscriber
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct LogCfg {
+    pub level: tracing::Level,
+    /// Filter directives, e.g. "icap_poc=debug,hyper=warn"
+    pub filter: Option<String>,
+}
+
+impl Default for LogCfg {
+    fn default() -> Self {
+        LogCfg {
+            level: tracing::Level::INFO,
+            filter: None,
+        }
+    }
+}
+
+impl FileCfg {
+    /// Reads and parses the configuration file at `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, FileCfgError> {
+        std::fs::read_to_string(path)?.parse()
+    }
+}
+
+impl FromStr for FileCfg {
+    type Err = FileCfgError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let raw: RawFileCfg = toml::from_str(s).map_err(|e| FileCfgError::Parse(e.to_string()))?;
+        let invalid = |span: std::ops::Range<usize>, msg: String| FileCfgError::Invalid {
+            line: s[..span.start].matches('\n').count() + 1,
+            msg,
+        };
+
+        let builder = raw
+            .server
+            .builder()
+            .map_err(|(span, msg)| invalid(span, msg))?;
+        let log = raw
+            .log
+            .into_log_cfg()
+            .map_err(|(span, msg)| invalid(span, msg))?;
+
+        let mut paths = HashSet::new();
+        let mut services = Vec::with_capacity(raw.services.len());
+        for svc in raw.services {
+            let span = svc.span();
+            let svc = svc.into_inner();
+            if !svc.path.starts_with('/') {
+                return Err(invalid(span, format!("path '{}' not absolute", svc.path)));
+            }
+            if !paths.insert(svc.path.clone()) {
+                return Err(invalid(span, format!("duplicate path '{}'", svc.path)));
+            }
+            let path = svc.path.clone();
+            let options = svc.options().map_err(|(span, msg)| invalid(span, msg))?;
+            let options = options
+                .build()
+                .map_err(|e| invalid(span, format!("service '{}': {}", path, e)))?;
+            services.push(FileServiceCfg {
+                path,
+                cfg: builder.clone().options(options).build(),
+            });
+        }
+
+        Ok(FileCfg {
+            listen: raw.server.listen,
+            server: builder.build(),
+            services,
+            log,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFileCfg {
+    #[serde(default)]
+    server: RawServer,
+    #[serde(default)]
+    log: RawLog,
+    #[serde(default, rename = "service")]
+    services: Vec<Spanned<RawService>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawServer {
+    #[serde(default)]
+    listen: Vec<SocketAddr>,
+    idle_timeout: Option<Spanned<DurationValue>>,
+    read_timeout: Option<Spanned<DurationValue>>,
+    shutdown_timeout: Option<DurationValue>,
+    max_requests: Option<usize>,
+    max_connections: Option<Spanned<usize>>,
+    max_headers: Option<Spanned<usize>>,
+    rbuf_capacity: Option<usize>,
+    http_buf_capacity: Option<usize>,
+    body_buf_limit: Option<usize>,
+}
+
+impl RawServer {
+    fn builder(&self) -> Result<ServerCfgBuilder, SpannedError> {
+        let mut builder = ServerCfg::builder();
+        if let Some(v) = &self.idle_timeout {
+            builder = builder.idle_timeout(non_zero(v, "idle_timeout")?.0);
+        }
+        if let Some(v) = &self.read_timeout {
+            builder = builder.read_timeout(non_zero(v, "read_timeout")?.0);
+        }
+        if let Some(v) = self.shutdown_timeout {
+            builder = builder.shutdown_timeout(v.0);
+        }
+        if let Some(v) = self.max_requests {
+            builder = builder.max_requests(v);
+        }
+        if let Some(v) = &self.max_connections {
+            builder = builder.max_connections(non_zero(v, "max_connections")?);
+        }
+        if let Some(v) = &self.max_headers {
+            builder = builder.max_headers(non_zero(v, "max_headers")?);
+        }
+        if let Some(v) = self.rbuf_capacity {
+            builder = builder.rbuf_capacity(v);
+        }
+        if let Some(v) = self.http_buf_capacity {
+            builder = builder.http_buf_capacity(v);
+        }
+        if let Some(v) = self.body_buf_limit {
+            builder = builder.body_buf_limit(v);
+        }
+        Ok(builder)
+    }
+}
+
+type SpannedError = (std::ops::Range<usize>, String);
+
+/// Returns the value of setting `name`, which must not be zero
+fn non_zero<T: Copy + Default + PartialEq>(v: &Spanned<T>, name: &str) -> Result<T, SpannedError> {
+    if *v.get_ref() == T::default() {
+        return Err((v.span(), format!("{} must not be zero", name)));
+    }
+    Ok(*v.get_ref())
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLog {
+    level: Option<Spanned<String>>,
+    filter: Option<String>,
+}
+
+impl RawLog {
+    fn into_log_cfg(self) -> Result<LogCfg, SpannedError> {
+        let mut log = LogCfg {
+            filter: self.filter,
+            ..LogCfg::default()
+        };
+        if let Some(level) = self.level {
+            log.level = level
+                .get_ref()
+                .parse()
+                .map_err(|_| (level.span(), format!("bad log level '{}'", level.get_ref())))?;
+        }
+        Ok(log)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawService {
+    path: String,
+    istag: Option<String>,
+    methods: Option<Vec<Spanned<String>>>,
+    preview: Option<PreviewValue>,
+    service: Option<String>,
+    service_id: Option<String>,
+    options_ttl: Option<DurationValue>,
+    max_connections: Option<u32>,
+    transfer_preview: Option<Vec<String>>,
+    transfer_ignore: Option<Vec<String>>,
+    transfer_complete: Option<Vec<String>>,
+    allow_204: Option<bool>,
+    allow_206: Option<bool>,
+    allow_trailers: Option<bool>,
+}
+
+impl RawService {
+    fn options(self) -> Result<OptionsResponseBuilder, SpannedError> {
+        let mut builder = OptionsResponse::builder();
+        if let Some(methods) = self.methods {
+            let methods = methods
+                .iter()
+                .map(|m| {
+                    Method::from_str(m.get_ref())
+                        .map_err(|_| (m.span(), format!("bad ICAP method '{}'", m.get_ref())))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            builder = builder.methods(&methods);
+        }
+        if let Some(v) = self.istag {
+            builder = builder.is_tag(v);
+        }
+        if let Some(v) = self.preview {
+            builder = builder.preview(v.0);
+        }
+        if let Some(v) = self.service {
+            builder = builder.service(v);
+        }
+        if let Some(v) = self.service_id {
+            builder = builder.service_id(v);
+        }
+        if let Some(v) = self.options_ttl {
+            builder = builder.options_ttl(v.0);
+        }
+        if let Some(v) = self.max_connections {
+            builder = builder.max_connections(v);
+        }
+        fn as_strs(exts: &[String]) -> Vec<&str> {
+            exts.iter().map(String::as_str).collect()
+        }
+        if let Some(v) = self.transfer_preview {
+            builder = builder.transfer_preview(&as_strs(&v));
+        }
+        if let Some(v) = self.transfer_ignore {
+            builder = builder.transfer_ignore(&as_strs(&v));
+        }
+        if let Some(v) = self.transfer_complete {
+            builder = builder.transfer_complete(&as_strs(&v));
+        }
+        if let Some(v) = self.allow_204 {
+            builder = builder.allow_204(v);
+        }
+        if let Some(v) = self.allow_206 {
+            builder = builder.allow_206(v);
+        }
+        if let Some(v) = self.allow_trailers {
+            builder = builder.allow_trailers(v);
+        }
+        Ok(builder)
+    }
+}
+
+/// Duration given in seconds, or as a string with unit ms, s, m or h
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+struct DurationValue(Duration);
+
+impl<'de> Deserialize<'de> for DurationValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct DurationVisitor;
+
+        impl<'de> Visitor<'de> for DurationVisitor {
+            type Value = DurationValue;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("seconds or a duration string like \"500ms\", \"10s\", \"5m\", \"1h\"")
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                u64::try_from(v)
+                    .map(|secs| DurationValue(Duration::from_secs(secs)))
+                    .map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                let split = v.find(|c: char| !c.is_ascii_digit()).unwrap_or(v.len());
+                let (n, unit) = v.split_at(split);
+                let n: u64 = n
+                    .parse()
+                    .map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self))?;
+                let duration = match unit.trim() {
+                    "ms" => Some(Duration::from_millis(n)),
+                    "s" => Some(Duration::from_secs(n)),
+                    "m" => n.checked_mul(60).map(Duration::from_secs),
+                    "h" => n.checked_mul(3600).map(Duration::from_secs),
+                    _ => None,
+                };
+                duration
+                    .map(DurationValue)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Str(v), &self))
+            }
+        }
+
+        deserializer.deserialize_any(DurationVisitor)
+    }
+}
+
+/// Preview size, or `false` to disable preview
+#[derive(Debug, Copy, Clone)]
+struct PreviewValue(Option<usize>);
+
+impl<'de> Deserialize<'de> for PreviewValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PreviewVisitor;
+
+        impl<'de> Visitor<'de> for PreviewVisitor {
+            type Value = PreviewValue;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a preview size or false")
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                usize::try_from(v)
+                    .map(|size| PreviewValue(Some(size)))
+                    .map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+            }
+
+            fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
+                if v {
+                    return Err(E::invalid_value(de::Unexpected::Bool(v), &self));
+                }
+                Ok(PreviewValue(None))
+            }
+        }
+
+        deserializer.deserialize_any(PreviewVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let cfg: FileCfg = r#"
+            [server]
+            listen = ["127.0.0.1:1344", "[::1]:1344"]
+            idle_timeout = "500ms"
+            read_timeout = 5
+            max_requests = 100
+            max_connections = 50
+
+            [log]
+            level = "debug"
+
+            [[service]]
+            path = "/reqmod"
+            istag = "policy-1"
+            methods = ["REQMOD"]
+            preview = 1024
+            options_ttl = "1h"
+
+            [[service]]
+            path = "/respmod"
+            preview = false
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(cfg.listen.len(), 2);
+        assert_eq!(cfg.server.idle_timeout(), Duration::from_millis(500));
+        assert_eq!(cfg.server.read_timeout(), Duration::from_secs(5));
+        assert_eq!(cfg.log.level, tracing::Level::DEBUG);
+        assert_eq!(cfg.services.len(), 2);
+
+        let svc = &cfg.services[0];
+        assert_eq!(svc.path, "/reqmod");
+        assert_eq!(svc.cfg.max_requests(), Some(100));
+        assert_eq!(svc.cfg.max_connections(), Some(50));
+        assert_eq!(svc.cfg.options().is_tag(), "\"policy-1\"");
+        assert_eq!(svc.cfg.options().preview(), Some(1024));
+        assert_eq!(cfg.services[1].cfg.options().preview(), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = |s: &str| s.parse::<FileCfg>().unwrap_err().to_string();
+
+        let e = err("[server]\nidel_timeout = 5\n");
+        assert!(e.contains("line 2"), "{}", e);
+        assert!(e.contains("unknown field `idel_timeout`"), "{}", e);
+
+        let e = err("[server]\nmax_requests = 1\nidle_timeout = \"5 days\"\n");
+        assert!(e.contains("line 3"), "{}", e);
+
+        let e = err("[server]\nread_timeout = \"6000000000000000000h\"\n");
+        assert!(e.contains("line 2"), "{}", e);
+        assert!(e.contains("invalid value"), "{}", e);
+
+        let e = err("[[service]]\npath = \"/a\"\n\n[[service]]\npath = \"/b\"\nistag = \"a b\"\n");
+        assert_eq!(e, "line 4: service '/b': bad ISTag: a b (characters)");
+
+        let e = err("[[service]]\npath = \"/a\"\nmethods = [\n\"REQMOD\",\n\"GET\"]\n");
+        assert_eq!(e, "line 5: bad ICAP method 'GET'");
+
+        let e = err("[[service]]\npath = \"/a\"\n[[service]]\npath = \"/a\"\n");
+        assert_eq!(e, "line 3: duplicate path '/a'");
+
+        let e = err("[server]\nmax_headers = 64\nread_timeout = \"0ms\"\n");
+        assert_eq!(e, "line 3: read_timeout must not be zero");
+
+        for name in ["idle_timeout", "max_connections", "max_headers"] {
+            let e = err(&format!("[server]\n{} = 0\n", name));
+            assert_eq!(e, format!("line 2: {} must not be zero", name));
+        }
+
+        let e = err("[log]\nlevel = \"loud\"\n");
+        assert_eq!(e, "line 2: bad log level 'loud'");
+    }
+}
@@ -0,0 +1,473 @@
+use crate::{errors::OptionsError, server::DEFAULT_IS_TAG, Method};
+use http::{HeaderMap, HeaderValue};
+use std::time::{Duration, SystemTime};
+
+const MAX_IS_TAG_LEN: usize = 32;
+
+/// Transfer policy of a file extension, advertised in the Transfer-* OPTIONS headers
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Transfer {
+    // send a preview
+    Preview,
+    // don't send the file, the server answers 204 if sent anyway
+    Ignore,
+    // send the entire file without preview
+    Complete,
+}
+
+/// OPTIONS response advertised by a service
+///
+/// Headers set by `handle_options` take precedence over the ones generated from here.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OptionsResponse {
+    methods: HeaderValue,
+    is_tag: HeaderValue,
+    service: Option<HeaderValue>,
+    service_id: Option<HeaderValue>,
+    options_ttl: Option<u32>,
+    max_connections: Option<u32>,
+    preview: Option<usize>,
+    transfer_preview: Option<HeaderValue>,
+    transfer_ignore: Option<HeaderValue>,
+    transfer_complete: Option<HeaderValue>,
+    transfer_lists: [(Transfer, Vec<String>); 3],
+    allow_204: bool,
+    allow_206: bool,
+    allow_trailers: bool,
+    date: bool,
+}
+
+impl OptionsResponse {
+    #[inline]
+    pub fn builder() -> OptionsResponseBuilder {
+        OptionsResponseBuilder::default()
+    }
+
+    #[inline]
+    pub fn is_tag(&self) -> &HeaderValue {
+        &self.is_tag
+    }
+
+    #[inline]
+    pub fn max_connections(&self) -> Option<u32> {
+        self.max_connections
+    }
+
+    #[inline]
+    pub fn preview(&self) -> Option<usize> {
+        self.preview
+    }
+
+    /// Returns the transfer policy of the file extension `ext` (case-insensitive),
+    /// `None` stands for a file without extension
+    ///
+    /// Extensions not found in any list fall under the list with "*", or Preview.
+    pub fn transfer(&self, ext: Option<&str>) -> Transfer {
+        let mut wildcard = Transfer::Preview;
+        for (transfer, exts) in &self.transfer_lists {
+            for e in exts {
+                if e == "*" {
+                    wildcard = *transfer;
+                } else if ext.is_some_and(|ext| ext.eq_ignore_ascii_case(e)) {
+                    return *transfer;
+                }
+            }
+        }
+        wildcard
+    }
+
+    /// Adds the OPTIONS response headers missing in `headers`,
+    /// 'Allow: 206' and 'Allow: trailers' are added only if the client allows them
+    pub(crate) fn encode(&self, headers: &mut HeaderMap, client_206: bool, client_trailers: bool) {
+        let mut allow = Vec::with_capacity(3);
+        if self.allow_204 {
+            allow.push("204");
+        }
+        if self.allow_206 && client_206 {
+            allow.push("206");
+        }
+        if self.allow_trailers && client_trailers {
+            allow.push("trailers");
+        }
+
+        let mut insert = |name: &'static str, val: Option<HeaderValue>| {
+            if let Some(val) = val {
+                headers.entry(name).or_insert(val);
+            }
+        };
+        insert("Methods", Some(self.methods.clone()));
+        insert("ISTag", Some(self.is_tag.clone()));
+        insert("Service", self.service.clone());
+        insert("Service-ID", self.service_id.clone());
+        insert("Options-TTL", self.options_ttl.map(HeaderValue::from));
+        insert(
+            "Max-Connections",
+            self.max_connections.map(HeaderValue::from),
+        );
+        insert("Preview", self.preview.map(HeaderValue::from));
+        insert("Transfer-Preview", self.transfer_preview.clone());
+        insert("Transfer-Ignore", self.transfer_ignore.clone());
+        insert("Transfer-Complete", self.transfer_complete.clone());
+        if !allow.is_empty() {
+            insert("Allow", HeaderValue::from_str(&allow.join(", ")).ok());
+        }
+        if self.date {
+            let date = httpdate::fmt_http_date(SystemTime::now());
+            insert("Date", HeaderValue::from_str(&date).ok());
+        }
+    }
+}
+
+impl Default for OptionsResponse {
+    #[inline]
+    fn default() -> Self {
+        OptionsResponseBuilder::default()
+            .build()
+            .expect("default builder values are valid")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OptionsResponseBuilder {
+    methods: Vec<Method>,
+    is_tag: String,
+    service: Option<String>,
+    service_id: Option<String>,
+    options_ttl: Option<Duration>,
+    max_connections: Option<u32>,
+    preview: Option<usize>,
+    transfer_preview: Vec<String>,
+    transfer_ignore: Vec<String>,
+    transfer_complete: Vec<String>,
+    allow_204: bool,
+    allow_206: bool,
+    allow_trailers: bool,
+    date: bool,
+}
+
+impl Default for OptionsResponseBuilder {
+    fn default() -> Self {
+        Self {
+            methods: vec![Method::ReqMod, Method::RespMod],
+            is_tag: DEFAULT_IS_TAG.into(),
+            service: None,
+            service_id: None,
+            options_ttl: None,
+            max_connections: None,
+            preview: Some(0),
+            transfer_preview: vec!["*".into()],
+            transfer_ignore: Vec::new(),
+            transfer_complete: Vec::new(),
+            allow_204: true,
+            allow_206: true,
+            allow_trailers: true,
+            date: true,
+        }
+    }
+}
+
+impl OptionsResponseBuilder {
+    #[inline]
+    pub fn methods(mut self, methods: &[Method]) -> Self {
+        self.methods = methods.to_vec();
+        self
+    }
+
+    /// Sets the ISTag, up to 32 characters, quoted or not
+    #[inline]
+    pub fn is_tag(mut self, is_tag: impl Into<String>) -> Self {
+        self.is_tag = is_tag.into();
+        self
+    }
+
+    #[inline]
+    pub fn service(mut self, service: impl Into<String>) -> Self {
+        self.service = Some(service.into());
+        self
+    }
+
+    #[inline]
+    pub fn service_id(mut self, service_id: impl Into<String>) -> Self {
+        self.service_id = Some(service_id.into());
+        self
+    }
+
+    /// Sets Options-TTL, rounded down to seconds
+    #[inline]
+    pub fn options_ttl(mut self, ttl: Duration) -> Self {
+        self.options_ttl = Some(ttl);
+        self
+    }
+
+    #[inline]
+    pub fn max_connections(mut self, max_connections: u32) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Sets the preview size, `None` disables preview
+    #[inline]
+    pub fn preview(mut self, preview: Option<usize>) -> Self {
+        self.preview = preview;
+        self
+    }
+
+    /// Sets the file extensions to preview, "*" stands for all the others
+    #[inline]
+    pub fn transfer_preview(mut self, exts: &[&str]) -> Self {
+        self.transfer_preview = exts.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    #[inline]
+    pub fn transfer_ignore(mut self, exts: &[&str]) -> Self {
+        self.transfer_ignore = exts.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    #[inline]
+    pub fn transfer_complete(mut self, exts: &[&str]) -> Self {
+        self.transfer_complete = exts.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    #[inline]
+    pub fn allow_204(mut self, allow: bool) -> Self {
+        self.allow_204 = allow;
+        self
+    }
+
+    /// Advertises 206 support to clients sending 'Allow: 206'
+    #[inline]
+    pub fn allow_206(mut self, allow: bool) -> Self {
+        self.allow_206 = allow;
+        self
+    }
+
+    /// Advertises trailers support to clients sending 'Allow: trailers'
+    #[inline]
+    pub fn allow_trailers(mut self, allow: bool) -> Self {
+        self.allow_trailers = allow;
+        self
+    }
+
+    /// Adds the 'Date' header
+    #[inline]
+    pub fn date(mut self, date: bool) -> Self {
+        self.date = date;
+        self
+    }
+
+    pub fn build(self) -> Result<OptionsResponse, OptionsError> {
+        if self.methods.is_empty() {
+            return Err(OptionsError::NoMethods);
+        }
+        if self.methods.contains(&Method::Options) {
+            return Err(OptionsError::BadMethod);
+        }
+        let methods = self
+            .methods
+            .iter()
+            .map(|m| m.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let is_tag = encode_is_tag(&self.is_tag)?;
+
+        let service = match self.service {
+            Some(s) => {
+                Some(HeaderValue::try_from(s).map_err(|_| OptionsError::BadValue("Service"))?)
+            }
+            None => None,
+        };
+
+        let service_id = match self.service_id {
+            Some(s) if !s.is_empty() && s.bytes().all(is_token) => {
+                Some(HeaderValue::try_from(s).expect("token characters are valid"))
+            }
+            Some(_) => return Err(OptionsError::BadValue("Service-ID")),
+            None => None,
+        };
+
+        let options_ttl = match self.options_ttl {
+            Some(ttl) if ttl.as_secs() == 0 => return Err(OptionsError::ZeroValue("Options-TTL")),
+            Some(ttl) => Some(
+                u32::try_from(ttl.as_secs()).map_err(|_| OptionsError::BadValue("Options-TTL"))?,
+            ),
+            None => None,
+        };
+
+        if self.max_connections == Some(0) {
+            return Err(OptionsError::ZeroValue("Max-Connections"));
+        }
+
+        let lists = [
+            ("Transfer-Preview", &self.transfer_preview),
+            ("Transfer-Ignore", &self.transfer_ignore),
+            ("Transfer-Complete", &self.transfer_complete),
+        ];
+        let wildcards = lists
+            .iter()
+            .filter(|(_, l)| l.iter().any(|e| e == "*"))
+            .count();
+        if wildcards > 1 {
+            return Err(OptionsError::MultipleWildcards);
+        }
+        let [transfer_preview, transfer_ignore, transfer_complete] =
+            lists.map(|(name, list)| encode_transfer_list(name, list));
+        let lowercase = |exts: &[String]| exts.iter().map(|e| e.to_ascii_lowercase()).collect();
+        let transfer_lists = [
+            (Transfer::Complete, lowercase(&self.transfer_complete)),
+            (Transfer::Ignore, lowercase(&self.transfer_ignore)),
+            (Transfer::Preview, lowercase(&self.transfer_preview)),
+        ];
+
+        Ok(OptionsResponse {
+            methods: HeaderValue::try_from(methods).unwrap(),
+            is_tag,
+            service,
+            service_id,
+            options_ttl,
+            max_connections: self.max_connections,
+            preview: self.preview,
+            transfer_preview: transfer_preview?,
+            transfer_ignore: transfer_ignore?,
+            transfer_complete: transfer_complete?,
+            transfer_lists,
+            allow_204: self.allow_204,
+            allow_206: self.allow_206,
+            allow_trailers: self.allow_trailers,
+            date: self.date,
+        })
+    }
+}
+
+#[inline]
+fn is_token(b: u8) -> bool {
+    b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&b)
+}
+
+fn encode_is_tag(is_tag: &str) -> Result<HeaderValue, OptionsError> {
+    let bad = |reason: &str| OptionsError::BadIsTag(format!("{} ({})", is_tag, reason));
+
+    let tag = match is_tag.strip_prefix('"') {
+        Some(t) => t
+            .strip_suffix('"')
+            .ok_or_else(|| bad("unbalanced quotes"))?,
+        None => is_tag,
+    };
+    if tag.is_empty() || tag.len() > MAX_IS_TAG_LEN {
+        return Err(bad("length"));
+    }
+    if !tag
+        .bytes()
+        .all(|b| b.is_ascii_graphic() && b != b'"' && b != b'\\')
+    {
+        return Err(bad("characters"));
+    }
+    Ok(HeaderValue::try_from(format!("\"{}\"", tag)).expect("visible ASCII is valid"))
+}
+
+fn encode_transfer_list(
+    name: &'static str,
+    exts: &[String],
+) -> Result<Option<HeaderValue>, OptionsError> {
+    if exts.is_empty() {
+        return Ok(None);
+    }
+    if exts.len() > 1 && exts.iter().any(|e| e == "*") {
+        return Err(OptionsError::BadTransferList(name));
+    }
+    if !exts
+        .iter()
+        .all(|e| !e.is_empty() && e.bytes().all(is_token))
+    {
+        return Err(OptionsError::BadTransferList(name));
+    }
+    Ok(Some(
+        HeaderValue::try_from(exts.join(", ")).expect("token characters are valid"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build() {
+        let opts = OptionsResponse::builder()
+            .methods(&[Method::RespMod])
+            .is_tag("abc")
+            .service("Example AV 1.0")
+            .service_id("av")
+            .options_ttl(Duration::from_secs(3600))
+            .max_connections(100)
+            .transfer_preview(&["*"])
+            .transfer_ignore(&["jpg", "png"])
+            .date(false)
+            .build()
+            .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("Service", HeaderValue::from_static("custom"));
+        opts.encode(&mut headers, true, false);
+
+        assert_eq!(headers["Methods"], "RESPMOD");
+        assert_eq!(headers["ISTag"], "\"abc\"");
+        assert_eq!(headers["Service"], "custom");
+        assert_eq!(headers["Service-ID"], "av");
+        assert_eq!(headers["Options-TTL"], "3600");
+        assert_eq!(headers["Max-Connections"], "100");
+        assert_eq!(headers["Preview"], "0");
+        assert_eq!(headers["Transfer-Preview"], "*");
+        assert_eq!(headers["Transfer-Ignore"], "jpg, png");
+        assert_eq!(headers["Allow"], "204, 206");
+        assert!(!headers.contains_key("Transfer-Complete"));
+        assert!(!headers.contains_key("Date"));
+    }
+
+    #[test]
+    fn test_transfer() {
+        let opts = OptionsResponse::builder()
+            .transfer_preview(&["html"])
+            .transfer_ignore(&["*"])
+            .transfer_complete(&["EXE", "bat"])
+            .build()
+            .unwrap();
+        assert_eq!(opts.transfer(Some("HTML")), Transfer::Preview);
+        assert_eq!(opts.transfer(Some("exe")), Transfer::Complete);
+        assert_eq!(opts.transfer(Some("jpg")), Transfer::Ignore);
+        assert_eq!(opts.transfer(None), Transfer::Ignore);
+
+        let opts = OptionsResponse::default();
+        assert_eq!(opts.transfer(Some("jpg")), Transfer::Preview);
+    }
+
+    #[test]
+    fn test_validation() {
+        let b = OptionsResponse::builder;
+        assert_eq!(b().methods(&[]).build(), Err(OptionsError::NoMethods));
+        assert_eq!(
+            b().methods(&[Method::Options]).build(),
+            Err(OptionsError::BadMethod)
+        );
+        assert!(b().is_tag("").build().is_err());
+        assert!(b().is_tag("\"abc").build().is_err());
+        assert!(b().is_tag("a".repeat(33)).build().is_err());
+        assert!(b().is_tag("\"a b\"").build().is_err());
+        assert!(b().is_tag("\"ab\"").build().is_ok());
+        assert!(b().service("bad\r\n").build().is_err());
+        assert!(b().service_id("a b").build().is_err());
+        assert_eq!(
+            b().options_ttl(Duration::ZERO).build(),
+            Err(OptionsError::ZeroValue("Options-TTL"))
+        );
+        assert!(b().max_connections(0).build().is_err());
+        assert_eq!(
+            b().transfer_complete(&["*"]).build(),
+            Err(OptionsError::MultipleWildcards)
+        );
+        assert!(b().transfer_ignore(&["jpg", "*"]).build().is_err());
+        assert!(b().transfer_ignore(&["j,pg"]).build().is_err());
+    }
+}
@@ -1,37 +1,55 @@
 use crate::{
     common::{HttpRequest, IcapRequest},
     decoder::{
-        self, decode_allow, decode_preview, Allow, DecodingStatus, EeList, EncapsulatedEntity::*,
+        self, decode_allow, decode_preview, Allow, DecodingStatus, EeList, EncapsulatedEntity,
+        EncapsulatedEntity::*,
     },
     errors::DecoderError,
-    header::HeaderIterator,
+    header::{HeaderIndicesList, HeaderIterator},
+    server::{
+        config::{DEFAULT_HTTP_BUF_CAP, DEFAULT_MAX_HEADERS, DEFAULT_RBUF_CAP},
+        BlockPage, BodySink, BodyStream, ServerCfg, Transfer, BODY_CHANNEL_CAP,
+    },
     HttpResponse, Method,
 };
-use bytes::BytesMut;
+use bytes::{Buf, Bytes, BytesMut};
 use http::header::HeaderValue;
 use http::StatusCode;
-use std::boxed::Box;
+use std::{boxed::Box, net::IpAddr, sync::Arc};
+use tokio::sync::{mpsc, oneshot, watch};
 use tracing::{debug, error, trace, warn};
 
-pub(crate) const RBUF_CAP: usize = 8 * 1024;
-pub(crate) const HTTP_BUF_CAP: usize = RBUF_CAP;
 pub(crate) const DEFAULT_IS_TAG: &str = env!("DEFAULT_IS_TAG");
 pub type ReqCtxBox = Box<ReqCtx>;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum AdaptationDecision {
-    // no adaptation required, send 204
+    // no adaptation required, send 204,
+    // or 200 with the unmodified message when 204 is not allowed
     NoAdaptation,
-    // partial adaptation of headers required, send 206
+    // partial adaptation of headers required, send 206,
+    // or 200 with the original body when 206 is not allowed,
+    // a body prefix set by `set_http_body_prefix` replaces the start of the original body
     // appends headers to the HTTP request in REQMOD, or to the HTTP response in RESPMOD
     AppendHeaders,
-    // send a fully custom HTTP response
+    // send a fully custom HTTP response, with the body set by `set_http_body` if any
     CustomResponse,
+    // send the HTTP message with appended headers and a new body set by `set_http_body`,
+    // Content-Length is set to the new body length
+    ReplaceBody,
+    // the body received so far is not enough for a decision,
+    // receive the rest of the body (sending 100 Continue in preview) and call the handler again
+    NeedFullBody,
+    // stream the HTTP body through the `BodyStream` and `BodySink` returned by `transform_body`,
+    // send the HTTP message with appended headers and the transformed body
+    TransformBody,
 }
 
 #[derive(Debug)]
 pub struct ReqCtx {
     pub(crate) msgs_cnt: usize,
+    pub(crate) max_headers: usize,
+    pub(crate) cfg: Arc<ServerCfg>,
     pub(crate) rbuf: BytesMut,
     pub(crate) http_buf: BytesMut,
     pub(crate) icap_req: IcapRequest,
@@ -40,27 +58,58 @@ pub struct ReqCtx {
     pub(crate) ee_list: EeList,
     pub(crate) preview: Option<usize>,
     pub(crate) null_body: bool,
+    pub(crate) close: bool,
+    pub(crate) shutdown: Option<watch::Receiver<bool>>,
     pub(crate) allow_204: bool,
     pub(crate) allow_206: bool,
+    pub(crate) allow_trailers: bool,
+    pub(crate) expect_trailer: bool,
+    pub(crate) send_trailer: bool,
+    pub(crate) trailer: HeaderIndicesList,
+    pub(crate) trailer_buf: BytesMut,
     pub(crate) out_icap_status: Option<http::StatusCode>,
     pub(crate) out_icap_headers: http::HeaderMap,
     pub(crate) decision: Option<AdaptationDecision>,
     pub(crate) out_http_ver: Option<http::Version>,
+    pub(crate) out_http_method: Option<http::Method>,
+    pub(crate) out_http_uri: Option<http::Uri>,
     pub(crate) out_http_status: Option<http::StatusCode>,
     pub(crate) out_http_headers: http::HeaderMap,
+    pub(crate) out_http_removed: Vec<&'static str>,
+    pub(crate) out_http_replaced: http::HeaderMap,
+    pub(crate) out_http_body: Option<Bytes>,
+    pub(crate) out_http_body_prefix: Option<Bytes>,
+    pub(crate) original_body_offset: usize,
+    pub(crate) out_icap_trailers: http::HeaderMap,
+    pub(crate) out_icap_trailer_names: Vec<&'static str>,
     pub(crate) body_offset: usize,
+    pub(crate) body_consumed: usize,
     pub(crate) header_missing_bytes: usize,
+    pub(crate) body: BytesMut,
+    pub(crate) body_complete: bool,
+    pub(crate) continue_sent: bool,
+    pub(crate) chunk_left: usize,
+    pub(crate) chunk_crlf: bool,
+    pub(crate) body_in_tx: Option<mpsc::Sender<Bytes>>,
+    pub(crate) body_out_rx: Option<mpsc::Receiver<Bytes>>,
+    pub(crate) body_trailer_rx: Option<oneshot::Receiver<http::HeaderMap>>,
 }
 
 impl ReqCtx {
-    #[inline]
-    pub(crate) fn new() -> Self {
-        Self::default()
+    /// Creates a context with buffers and limits of the server configuration `cfg`
+    pub(crate) fn new(cfg: Arc<ServerCfg>) -> Self {
+        Self {
+            max_headers: cfg.max_headers,
+            rbuf: BytesMut::with_capacity(cfg.rbuf_capacity),
+            http_buf: BytesMut::with_capacity(cfg.http_buf_capacity),
+            cfg,
+            ..Self::default()
+        }
     }
 
     #[inline]
-    pub(crate) fn new_box() -> ReqCtxBox {
-        Box::new(Self::new())
+    pub(crate) fn new_box(cfg: Arc<ServerCfg>) -> ReqCtxBox {
+        Box::new(Self::new(cfg))
     }
 
     pub(crate) fn init(&mut self) -> Result<DecodingStatus, DecoderError> {
@@ -137,7 +186,12 @@ impl ReqCtx {
 
     fn decode_icap_request(&mut self) -> Result<DecodingStatus, DecoderError> {
         let base_ptr = self.rbuf.as_ptr() as usize;
-        match decoder::decode_icap_request(&self.rbuf, base_ptr, &mut self.icap_req) {
+        match decoder::decode_icap_request(
+            &self.rbuf,
+            base_ptr,
+            &mut self.icap_req,
+            self.max_headers,
+        ) {
             Ok(Some(parsed_len)) => {
                 trace!(len = parsed_len, "decoded icap request");
                 Ok(DecodingStatus::Complete)
@@ -156,7 +210,7 @@ impl ReqCtx {
     fn decode_http_request(&mut self, off: usize) -> Result<(), DecoderError> {
         let base_ptr = self.rbuf.as_ptr() as usize;
         let buf = &self.rbuf[(self.icap_req.parsed_len + off)..];
-        match decoder::decode_http_request(buf, base_ptr, &mut self.http_req) {
+        match decoder::decode_http_request(buf, base_ptr, &mut self.http_req, self.max_headers) {
             Ok(Some(parsed_len)) => {
                 trace!(len = parsed_len, "decoded http request");
                 Ok(())
@@ -175,7 +229,8 @@ impl ReqCtx {
     fn decode_http_response(&mut self, off: usize) -> Result<(), DecoderError> {
         let base_ptr = self.rbuf.as_ptr() as usize;
         let buf = &self.rbuf[(self.icap_req.parsed_len + off)..];
-        let parsed_len = decoder::decode_http_response(buf, base_ptr, &mut self.http_res)?;
+        let parsed_len =
+            decoder::decode_http_response(buf, base_ptr, &mut self.http_res, self.max_headers)?;
         trace!(len = parsed_len, "decoded http response");
         Ok(())
     }
@@ -187,6 +242,8 @@ impl ReqCtx {
         let mut preview = None;
         let mut allow: Option<Allow> = None;
         let mut null_body = false;
+        let mut has_trailer = false;
+        let mut close = false;
 
         for h in self.icap_req_headers() {
             if h.name == "Encapsulated" {
@@ -201,6 +258,11 @@ impl ReqCtx {
                     Some(ref mut a) => a.add(&tmp),
                     None => allow = Some(tmp),
                 }
+            } else if h.name == "Trailer" {
+                has_trailer = true;
+            } else if h.name == "Connection" && is_close(h.value.as_bytes()) {
+                trace!("client requested 'Connection: close'");
+                close = true;
             }
         }
 
@@ -235,10 +297,19 @@ impl ReqCtx {
             Some(a) => {
                 self.allow_204 = a.allow_204;
                 self.allow_206 = a.allow_206;
+                self.allow_trailers = a.allow_trailers;
             }
             None => trace!("no 'Allow' header found"),
         }
 
+        if has_trailer && !self.allow_trailers {
+            self.ee_list = ee_list;
+            error!("'Trailer' header without 'Allow: trailers'");
+            return Err(DecoderError::TrailerNotAllowed);
+        }
+        self.expect_trailer = has_trailer;
+        self.close = close;
+
         self.ee_list = ee_list;
         self.preview = preview;
         self.null_body = null_body;
@@ -285,8 +356,6 @@ impl ReqCtx {
     }
 
     fn check_sanity(&self) -> Result<(), DecoderError> {
-        let mut is_req = false;
-
         let good_ee = match self.icap_req.method {
             Method::Options => {
                 if self.ee_list.is_empty() {
@@ -298,7 +367,6 @@ impl ReqCtx {
                 }
             }
             Method::ReqMod => {
-                is_req = true;
                 if self.ee_list.len() != 2 {
                     false
                 } else {
@@ -306,21 +374,18 @@ impl ReqCtx {
                         && matches!(self.ee_list[1], ReqBody(_) | NullBody(_))
                 }
             }
-            Method::RespMod => {
-                is_req = true;
-                match self.ee_list.len() {
-                    2 => {
-                        matches!(self.ee_list[0], ResHdr(off) if off == 0)
-                            && matches!(self.ee_list[1], ResBody(_) | NullBody(_))
-                    }
-                    3 => {
-                        matches!(self.ee_list[0], ReqHdr(off) if off == 0)
-                            && matches!(self.ee_list[1], ResHdr(_))
-                            && matches!(self.ee_list[2], ResBody(_) | NullBody(_))
-                    }
-                    _ => false,
+            Method::RespMod => match self.ee_list.len() {
+                2 => {
+                    matches!(self.ee_list[0], ResHdr(off) if off == 0)
+                        && matches!(self.ee_list[1], ResBody(_) | NullBody(_))
                 }
-            }
+                3 => {
+                    matches!(self.ee_list[0], ReqHdr(off) if off == 0)
+                        && matches!(self.ee_list[1], ResHdr(_))
+                        && matches!(self.ee_list[2], ResBody(_) | NullBody(_))
+                }
+                _ => false,
+            },
         };
 
         if !good_ee {
@@ -328,22 +393,49 @@ impl ReqCtx {
             return Err(DecoderError::BadEncapsulatedHdr("unexpected ee_list"));
         }
 
-        if is_req && !self.null_body {
-            if !self.allow_206 {
-                error!("206 is not allowed on request with body");
-                return Err(DecoderError::NoAllow206);
-            }
-            let is_zero_preview = match self.preview {
-                Some(prv) => prv == 0,
-                None => false,
-            };
-            if !is_zero_preview {
-                error!("'Preview: 0' is not found on request with body");
-                return Err(DecoderError::NoPreview0);
-            }
+        Ok(())
+    }
+
+    /// Returns the offset in `rbuf` of the first not yet consumed body byte
+    #[inline]
+    pub(crate) fn body_buf_offset(&self) -> usize {
+        self.icap_req.parsed_len + self.body_offset + self.body_consumed
+    }
+
+    /// Marks `n` body bytes following [body_buf_offset](Self::body_buf_offset) as decoded
+    #[inline]
+    pub(crate) fn consume_body_bytes(&mut self, n: usize) {
+        debug_assert!(self.body_buf_offset() + n <= self.rbuf.len());
+        self.body_consumed += n;
+    }
+
+    /// Removes the consumed body bytes from `rbuf`, keeping the header bytes intact
+    pub(crate) fn compact_body_bytes(&mut self) {
+        if self.body_consumed == 0 {
+            return;
         }
+        let off = self.icap_req.parsed_len + self.body_offset;
+        let len = self.rbuf.len();
+        self.rbuf.copy_within((off + self.body_consumed)..len, off);
+        self.rbuf.truncate(len - self.body_consumed);
+        self.body_consumed = 0;
+    }
 
-        Ok(())
+    /// Returns the length of the current message in `rbuf`,
+    /// the bytes following it belong to the next pipelined message
+    #[inline]
+    fn msg_len(&self) -> usize {
+        if self.body_offset == usize::MAX {
+            self.icap_req.parsed_len
+        } else {
+            self.body_buf_offset()
+        }
+    }
+
+    /// Returns `true` if an ICAP trailer follows the body of this request
+    #[inline]
+    pub(crate) fn is_trailer_next(&self, ieof: bool) -> bool {
+        self.expect_trailer && (self.preview.is_none() || ieof || self.continue_sent)
     }
 
     #[inline]
@@ -393,6 +485,87 @@ impl ReqCtx {
         }
     }
 
+    /// Returns the ICAP trailer fields received after the body
+    #[inline]
+    pub fn icap_trailers(&self) -> HeaderIterator<'_> {
+        HeaderIterator {
+            buf: &self.trailer_buf,
+            iter: self.trailer.vec.iter(),
+        }
+    }
+
+    /// Returns the encapsulated HTTP request as `http::Request`
+    ///
+    /// In REQMOD the body is included once [complete](ReqCtx::is_body_complete),
+    /// otherwise it is empty.
+    pub fn to_http_request(&self) -> Result<Option<http::Request<Bytes>>, DecoderError> {
+        if self.http_req.parsed_len == 0 {
+            return Ok(None);
+        }
+        let headers =
+            to_header_map(self.http_req_headers()).ok_or(DecoderError::FailedToParseHttpReq)?;
+        let mut req = http::Request::new(self.complete_body(Method::ReqMod));
+        *req.method_mut() = self.http_req.method.clone();
+        *req.uri_mut() = self.http_req.uri.clone();
+        *req.version_mut() = self.http_req.version;
+        *req.headers_mut() = headers;
+        Ok(Some(req))
+    }
+
+    /// Returns the encapsulated HTTP response as `http::Response`
+    ///
+    /// In RESPMOD the body is included once [complete](ReqCtx::is_body_complete),
+    /// otherwise it is empty.
+    pub fn to_http_response(&self) -> Result<Option<http::Response<Bytes>>, DecoderError> {
+        if self.http_res.parsed_len == 0 {
+            return Ok(None);
+        }
+        let headers =
+            to_header_map(self.http_res_headers()).ok_or(DecoderError::FailedToParseHttpRes)?;
+        let mut res = http::Response::new(self.complete_body(Method::RespMod));
+        *res.status_mut() = self.http_res.status;
+        *res.version_mut() = self.http_res.version;
+        *res.headers_mut() = headers;
+        Ok(Some(res))
+    }
+
+    #[inline]
+    fn complete_body(&self, method: Method) -> Bytes {
+        if self.icap_req.method == method && self.body_complete {
+            Bytes::copy_from_slice(&self.body)
+        } else {
+            Bytes::new()
+        }
+    }
+
+    /// Returns the de-chunked encapsulated HTTP body received so far
+    #[inline]
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Returns `true` if [body](ReqCtx::body) holds the entire encapsulated HTTP body
+    ///
+    /// Without preview, bodies larger than the [body buffer limit](ServerCfg::body_buf_limit)
+    /// are passed to the handler partially.
+    #[inline]
+    pub fn is_body_complete(&self) -> bool {
+        self.body_complete
+    }
+
+    /// Returns the preview size requested by the client in the 'Preview' header
+    #[inline]
+    pub fn preview(&self) -> Option<usize> {
+        self.preview
+    }
+
+    /// Returns `true` while the request is in preview,
+    /// i.e. the client awaits either 100 Continue or a final response
+    #[inline]
+    pub fn is_preview(&self) -> bool {
+        self.preview.is_some() && !self.continue_sent
+    }
+
     #[inline]
     pub fn allow_204(&self) -> bool {
         self.allow_204
@@ -403,6 +576,95 @@ impl ReqCtx {
         self.allow_206
     }
 
+    #[inline]
+    pub fn allow_trailers(&self) -> bool {
+        self.allow_trailers
+    }
+
+    /// Returns the entities of the 'Encapsulated' ICAP header
+    #[inline]
+    pub fn encapsulated(&self) -> &[EncapsulatedEntity] {
+        self.ee_list.as_slice()
+    }
+
+    /// Returns the value of the first ICAP request header `name`
+    fn icap_req_header(&self, name: &str) -> Option<&[u8]> {
+        self.icap_req_headers().get(name).map(|v| v.as_bytes())
+    }
+
+    /// Returns the 'X-Client-IP' ICAP header
+    pub fn client_ip(&self) -> Result<Option<IpAddr>, DecoderError> {
+        const NAME: &str = "X-Client-IP";
+        self.icap_req_header(NAME)
+            .map(|v| decoder::decode_ip_addr(v, NAME))
+            .transpose()
+    }
+
+    /// Returns the 'X-Server-IP' ICAP header
+    pub fn server_ip(&self) -> Result<Option<IpAddr>, DecoderError> {
+        const NAME: &str = "X-Server-IP";
+        self.icap_req_header(NAME)
+            .map(|v| decoder::decode_ip_addr(v, NAME))
+            .transpose()
+    }
+
+    /// Returns the 'X-Client-Username' ICAP header, sent in plain text e.g. by Squid
+    pub fn client_username(&self) -> Result<Option<&str>, DecoderError> {
+        const NAME: &str = "X-Client-Username";
+        self.icap_req_header(NAME)
+            .map(|v| decoder::decode_str(v, NAME))
+            .transpose()
+    }
+
+    /// Returns the base64 decoded 'X-Authenticated-User' ICAP header,
+    /// e.g. "WinNT://domain/user"
+    pub fn authenticated_user(&self) -> Result<Option<String>, DecoderError> {
+        const NAME: &str = "X-Authenticated-User";
+        self.icap_req_header(NAME)
+            .map(|v| decoder::decode_base64(v, NAME))
+            .transpose()
+    }
+
+    /// Returns the base64 decoded, comma separated groups of the 'X-Authenticated-Groups'
+    /// ICAP header
+    pub fn authenticated_groups(&self) -> Result<Option<Vec<String>>, DecoderError> {
+        const NAME: &str = "X-Authenticated-Groups";
+        let groups = match self.icap_req_header(NAME) {
+            Some(v) => decoder::decode_base64(v, NAME)?,
+            None => return Ok(None),
+        };
+        Ok(Some(
+            groups
+                .split(',')
+                .map(str::trim)
+                .filter(|g| !g.is_empty())
+                .map(String::from)
+                .collect(),
+        ))
+    }
+
+    /// Returns the 'X-Subscriber-ID' ICAP header
+    pub fn subscriber_id(&self) -> Result<Option<&str>, DecoderError> {
+        const NAME: &str = "X-Subscriber-ID";
+        self.icap_req_header(NAME)
+            .map(|v| decoder::decode_str(v, NAME))
+            .transpose()
+    }
+
+    /// Returns `true` if a 204 response is allowed, i.e. the client sent 'Allow: 204',
+    /// or the request is in preview
+    #[inline]
+    pub fn can_204(&self) -> bool {
+        self.allow_204 || self.is_preview()
+    }
+
+    /// Returns `true` if a 206 response is allowed, i.e. the client sent 'Allow: 206',
+    /// and outside of preview also 'Allow: 204'
+    #[inline]
+    pub fn can_206(&self) -> bool {
+        self.allow_206 && self.can_204()
+    }
+
     #[inline]
     pub fn set_icap_status(&mut self, status: StatusCode) {
         self.out_icap_status = Some(status);
@@ -413,11 +675,145 @@ impl ReqCtx {
         self.out_http_status = Some(status);
     }
 
+    /// Sets the [CustomResponse](AdaptationDecision::CustomResponse) decision with `page`
+    /// rendered as the HTTP response body
+    ///
+    /// The HTTP status defaults to 403 Forbidden. The client IP is taken from
+    /// the 'X-Client-IP' ICAP header.
+    pub fn set_block_page(&mut self, page: &BlockPage, category: &str) {
+        let client_ip = match self.client_ip() {
+            Ok(Some(ip)) => ip.to_string(),
+            _ => String::new(),
+        };
+        let body = page.render(&self.http_req_url(), category, &client_ip);
+
+        self.out_http_status.get_or_insert(StatusCode::FORBIDDEN);
+        if !self
+            .out_http_headers
+            .contains_key(http::header::CONTENT_TYPE)
+        {
+            self.out_http_headers.insert(
+                http::header::CONTENT_TYPE,
+                HeaderValue::from_static("text/html; charset=utf-8"),
+            );
+        }
+        self.out_http_body = Some(body.into());
+        self.decision = Some(AdaptationDecision::CustomResponse);
+    }
+
+    /// Returns `true` if the file extension of the encapsulated HTTP request URI
+    /// is in the Transfer-Ignore list of the service
+    pub(crate) fn is_transfer_ignored(&self) -> bool {
+        if self.http_req.parsed_len == 0 {
+            return false;
+        }
+        let path = self.http_req.uri.path();
+        let file = &path[path.rfind('/').map_or(0, |i| i + 1)..];
+        let ext = file.rfind('.').map(|i| &file[(i + 1)..]);
+        self.cfg.options.transfer(ext) == Transfer::Ignore
+    }
+
+    /// Returns the URL of the encapsulated HTTP request in absolute form
+    fn http_req_url(&self) -> String {
+        if self.http_req.parsed_len == 0 {
+            return String::new();
+        }
+        let uri = &self.http_req.uri;
+        if uri.authority().is_some() {
+            return uri.to_string();
+        }
+        match self.http_req_headers().get("Host") {
+            Some(host) => format!("http://{}{}", String::from_utf8_lossy(host.as_bytes()), uri),
+            None => uri.to_string(),
+        }
+    }
+
+    /// Sets the [CustomResponse](AdaptationDecision::CustomResponse) decision with `res`
+    ///
+    /// Status, version, headers and body of `res` replace any previously set ones.
+    /// The body is sent with 'Content-Length', except for statuses without a body.
+    pub fn set_http_response<B: Into<Bytes>>(&mut self, res: http::Response<B>) {
+        let (parts, body) = res.into_parts();
+        let status = parts.status;
+        self.out_http_status = Some(status);
+        self.out_http_ver = Some(parts.version);
+        self.out_http_headers = parts.headers;
+        self.out_http_removed.clear();
+        self.out_http_replaced.clear();
+        self.out_http_body = if status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+        {
+            None
+        } else {
+            Some(body.into())
+        };
+        self.decision = Some(AdaptationDecision::CustomResponse);
+    }
+
+    /// Sets the method of the adapted HTTP request in REQMOD
+    #[inline]
+    pub fn set_http_method(&mut self, method: http::Method) {
+        self.out_http_method = Some(method);
+    }
+
+    /// Sets the target URI of the adapted HTTP request in REQMOD
+    ///
+    /// The 'Host' header is not updated, use `set_http_header` if the authority changes.
+    #[inline]
+    pub fn set_http_uri(&mut self, uri: http::Uri) {
+        self.out_http_uri = Some(uri);
+    }
+
+    #[inline]
+    pub fn set_http_body(&mut self, body: impl Into<Bytes>) {
+        self.out_http_body = Some(body.into());
+    }
+
+    /// Sets the adapted body prefix, followed by the original body starting at `original_offset`
+    ///
+    /// E.g. a banner is injected with `original_offset` 0,
+    /// and the first N bytes are replaced with `original_offset` N.
+    #[inline]
+    pub fn set_http_body_prefix(&mut self, prefix: impl Into<Bytes>, original_offset: usize) {
+        self.out_http_body_prefix = Some(prefix.into());
+        self.original_body_offset = original_offset;
+    }
+
+    /// Returns the original body length, known when the body is complete
+    /// or from the 'Content-Length' header of the encapsulated message
+    pub(crate) fn original_body_len(&self) -> Option<usize> {
+        if self.body_complete {
+            return Some(self.body.len());
+        }
+        let headers = match self.icap_req.method {
+            Method::ReqMod => self.http_req_headers(),
+            _ => self.http_res_headers(),
+        };
+        headers.get("Content-Length")?.parse().ok()
+    }
+
     #[inline]
     pub fn set_decision(&mut self, decision: AdaptationDecision) {
         self.decision = Some(decision);
     }
 
+    /// Sets the [TransformBody](AdaptationDecision::TransformBody) decision and returns
+    /// the stream of incoming body chunks and the sink for the transformed ones
+    ///
+    /// The stream starts with the body received so far.
+    /// The handler is expected to move both into a spawned task and return.
+    pub fn transform_body(&mut self) -> (BodyStream, BodySink) {
+        let (in_tx, in_rx) = mpsc::channel(BODY_CHANNEL_CAP);
+        let (out_tx, out_rx) = mpsc::channel(BODY_CHANNEL_CAP);
+        let (trailer_tx, trailer_rx) = oneshot::channel();
+        self.body_in_tx = Some(in_tx);
+        self.body_out_rx = Some(out_rx);
+        self.body_trailer_rx = Some(trailer_rx);
+        self.decision = Some(AdaptationDecision::TransformBody);
+        (BodyStream::new(in_rx), BodySink::new(out_tx, trailer_tx))
+    }
+
     #[inline]
     pub fn append_icap_res_header(&mut self, name: &'static str, val: &'static str) {
         self.out_icap_headers
@@ -440,66 +836,239 @@ impl ReqCtx {
         self.out_http_headers.append(name, val);
     }
 
+    /// Removes all headers named `name` from the adapted HTTP message
+    pub fn remove_http_header(&mut self, name: &'static str) {
+        self.out_http_headers.remove(name);
+        self.out_http_replaced.remove(name);
+        self.out_http_removed.push(name);
+    }
+
+    /// Replaces the value of the header named `name` in the adapted HTTP message,
+    /// keeping its position among the original headers
+    ///
+    /// The header is appended if the original message doesn't have it.
+    #[inline]
+    pub fn set_http_header(&mut self, name: &'static str, val: &'static str) {
+        self.set_http_header_val(name, HeaderValue::from_static(val));
+    }
+
+    pub fn set_http_header_val(&mut self, name: &'static str, val: HeaderValue) {
+        self.out_http_headers.remove(name);
+        self.out_http_removed
+            .retain(|n| !n.eq_ignore_ascii_case(name));
+        self.out_http_replaced.insert(name, val);
+    }
+
+    /// Appends a field to the ICAP trailer sent after the response body
+    ///
+    /// Trailers are sent only if the client sent 'Allow: trailers'.
+    #[inline]
+    pub fn append_icap_trailer(&mut self, name: &'static str, val: &'static str) {
+        self.out_icap_trailers
+            .append(name, HeaderValue::from_static(val));
+    }
+
+    #[inline]
+    pub fn append_icap_trailer_val(&mut self, name: &'static str, val: HeaderValue) {
+        self.out_icap_trailers.append(name, val);
+    }
+
+    /// Declares the name of a trailer field set later with `BodySink::finish_with_trailers`
+    #[inline]
+    pub fn declare_icap_trailer(&mut self, name: &'static str) {
+        self.out_icap_trailer_names.push(name);
+    }
+
     pub(crate) fn clear(&mut self) {
-        self.rbuf.clear();
+        // keep the already received bytes of the next message
+        let msg_len = self.msg_len().min(self.rbuf.len());
+        self.rbuf.advance(msg_len);
+        if !self.rbuf.is_empty() {
+            trace!(len = self.rbuf.len(), "kept pipelined bytes");
+        }
         self.icap_req.clear();
         self.http_req.clear();
         self.http_res.clear();
         self.ee_list.clear();
         self.preview = None;
         self.null_body = false;
+        self.close = false;
         self.allow_204 = false;
         self.allow_206 = false;
+        self.allow_trailers = false;
+        self.expect_trailer = false;
+        self.send_trailer = false;
+        self.trailer.clear();
+        self.trailer_buf.clear();
         self.decision = None;
         self.out_icap_headers.clear();
         self.out_icap_status = None;
         self.out_http_status = None;
         self.out_http_headers.clear();
+        self.out_http_removed.clear();
+        self.out_http_replaced.clear();
         self.out_http_ver = None;
+        self.out_http_method = None;
+        self.out_http_uri = None;
+        self.out_http_body = None;
+        self.out_http_body_prefix = None;
+        self.original_body_offset = 0;
+        self.out_icap_trailers.clear();
+        self.out_icap_trailer_names.clear();
         self.body_offset = 0;
+        self.body_consumed = 0;
         self.header_missing_bytes = 0;
+        self.body.clear();
+        self.body_complete = false;
+        self.continue_sent = false;
+        self.chunk_left = 0;
+        self.chunk_crlf = false;
+        self.body_in_tx = None;
+        self.body_out_rx = None;
+        self.body_trailer_rx = None;
+    }
+
+    /// Returns `true` if the client asked to close the connection,
+    /// the request limit is reached, or the server is shutting down
+    #[inline]
+    fn is_closing(&self) -> bool {
+        self.close || self.shutdown.as_ref().is_some_and(|rx| *rx.borrow())
     }
 
-    pub(crate) fn ensure_options_headers(&mut self) {
+    /// Returns `true` if the connection has to be closed after this message
+    pub(crate) fn is_last_msg(&self) -> bool {
+        self.is_closing()
+            || self
+                .out_icap_headers
+                .get("Connection")
+                .is_some_and(|v| is_close(v.as_bytes()))
+    }
+
+    #[inline]
+    fn connection_header(&self) -> &'static str {
+        if self.is_closing() {
+            "close"
+        } else {
+            "keep-alive"
+        }
+    }
+
+    /// Adds the missing OPTIONS response headers,
+    /// `max_connections` is the connection limit enforced by the server
+    pub(crate) fn ensure_options_headers(&mut self, max_connections: Option<usize>) {
         for (k, v) in &[
             ("Encapsulated", "null-body=0"),
-            ("Methods", "REQMOD, RESPMOD"),
-            ("Allow", "204, 206"),
-            ("ISTag", DEFAULT_IS_TAG),
             ("Server", "r-bk/icap"),
-            ("Preview", "0"),
-            ("Transfer-Preview", "*"),
-            ("Connection", "keep-alive"),
+            ("Connection", self.connection_header()),
         ] {
             self.out_icap_headers
                 .entry(*k)
                 .or_insert(HeaderValue::from_static(v));
         }
+        // the server limit takes precedence over a higher one of the service
+        if let Some(max) = max_connections {
+            let advertised = self.cfg.options.max_connections().map(|m| m as usize);
+            if advertised.is_none_or(|m| m > max) {
+                self.out_icap_headers
+                    .entry("Max-Connections")
+                    .or_insert(HeaderValue::from(max));
+            }
+        }
+        // advertise 206 and trailers only to clients supporting them
+        self.cfg.options.encode(
+            &mut self.out_icap_headers,
+            self.allow_206,
+            self.allow_trailers,
+        );
     }
 
     pub(crate) fn ensure_204_headers(&mut self) {
         for (k, v) in &[
             ("Encapsulated", "null-body=0"),
-            ("ISTag", DEFAULT_IS_TAG),
             ("Server", "r-bk/icap"),
-            ("Connection", "keep-alive"),
+            ("Connection", self.connection_header()),
         ] {
             self.out_icap_headers
                 .entry(*k)
                 .or_insert(HeaderValue::from_static(v));
         }
+        self.ensure_is_tag_header();
     }
 
     pub(crate) fn ensure_response_headers(&mut self) {
         for (k, v) in &[
-            ("ISTag", DEFAULT_IS_TAG),
             ("Server", "r-bk/icap"),
-            ("Connection", "keep-alive"),
+            ("Connection", self.connection_header()),
         ] {
             self.out_icap_headers
                 .entry(*k)
                 .or_insert(HeaderValue::from_static(v));
         }
+        self.ensure_is_tag_header();
+        self.ensure_trailer_headers();
+    }
+
+    #[inline]
+    fn ensure_is_tag_header(&mut self) {
+        self.out_icap_headers
+            .entry("ISTag")
+            .or_insert_with(|| self.cfg.options.is_tag().clone());
+    }
+
+    /// Announces the ICAP trailer with 'Allow: trailers' and 'Trailer' headers,
+    /// if the handler set trailer fields and the client supports trailers
+    fn ensure_trailer_headers(&mut self) {
+        if self.out_icap_trailers.is_empty() && self.out_icap_trailer_names.is_empty() {
+            return;
+        }
+        if !self.allow_trailers {
+            debug!("trailers not allowed, dropping ICAP trailer");
+            return;
+        }
+
+        let mut names = String::new();
+        for name in self.out_icap_trailers.keys() {
+            if !names.is_empty() {
+                names.push_str(", ");
+            }
+            names.push_str(name.as_str());
+        }
+        for name in &self.out_icap_trailer_names {
+            if self.out_icap_trailers.contains_key(*name) {
+                continue;
+            }
+            if !names.is_empty() {
+                names.push_str(", ");
+            }
+            names.push_str(name);
+        }
+
+        match HeaderValue::try_from(names) {
+            Ok(v) => {
+                self.add_allow_trailers();
+                self.out_icap_headers.insert("Trailer", v);
+                self.send_trailer = true;
+            }
+            Err(e) => error!(err = %e, "bad ICAP trailer names"),
+        }
+    }
+
+    /// Adds the 'trailers' token to the 'Allow' header set by the handler, if any
+    fn add_allow_trailers(&mut self) {
+        let mut allow = Vec::new();
+        for v in self.out_icap_headers.get_all("Allow") {
+            let v = v.as_bytes();
+            if v.split(|b| *b == b',')
+                .any(|t| t.trim_ascii().eq_ignore_ascii_case(b"trailers"))
+            {
+                return;
+            }
+            allow.extend_from_slice(v);
+            allow.extend_from_slice(b", ");
+        }
+        allow.extend_from_slice(b"trailers");
+        let v = HeaderValue::from_bytes(&allow).expect("joined header values are valid");
+        self.out_icap_headers.insert("Allow", v);
     }
 }
 
@@ -508,24 +1077,69 @@ impl Default for ReqCtx {
     fn default() -> Self {
         Self {
             msgs_cnt: 0,
-            rbuf: BytesMut::with_capacity(RBUF_CAP),
-            http_buf: BytesMut::with_capacity(HTTP_BUF_CAP),
+            max_headers: DEFAULT_MAX_HEADERS,
+            cfg: Default::default(),
+            rbuf: BytesMut::with_capacity(DEFAULT_RBUF_CAP),
+            http_buf: BytesMut::with_capacity(DEFAULT_HTTP_BUF_CAP),
             icap_req: IcapRequest::default(),
             http_req: HttpRequest::default(),
             http_res: HttpResponse::default(),
             ee_list: EeList::default(),
             preview: None,
             null_body: false,
+            close: false,
+            shutdown: None,
             allow_204: false,
             allow_206: false,
+            allow_trailers: false,
+            expect_trailer: false,
+            send_trailer: false,
+            trailer: HeaderIndicesList::default(),
+            trailer_buf: BytesMut::new(),
             decision: None,
             out_icap_headers: Default::default(),
             out_icap_status: Default::default(),
             out_http_status: None,
             out_http_headers: Default::default(),
+            out_http_removed: Vec::new(),
+            out_http_replaced: Default::default(),
             out_http_ver: None,
+            out_http_method: None,
+            out_http_uri: None,
+            out_http_body: None,
+            out_http_body_prefix: None,
+            original_body_offset: 0,
+            out_icap_trailers: Default::default(),
+            out_icap_trailer_names: Vec::new(),
             body_offset: 0,
+            body_consumed: 0,
             header_missing_bytes: 0,
+            body: BytesMut::new(),
+            body_complete: false,
+            continue_sent: false,
+            chunk_left: 0,
+            chunk_crlf: false,
+            body_in_tx: None,
+            body_out_rx: None,
+            body_trailer_rx: None,
         }
     }
 }
+/// Converts parsed headers to `http::HeaderMap`, `None` if a header is not valid there
+fn to_header_map(headers: HeaderIterator<'_>) -> Option<http::HeaderMap> {
+    let mut map = http::HeaderMap::with_capacity(headers.iter.len());
+    for h in headers {
+        let name = http::HeaderName::from_bytes(h.name.as_bytes()).ok()?;
+        let value = HeaderValue::from_bytes(h.value.as_bytes()).ok()?;
+        map.append(name, value);
+    }
+    Some(map)
+}
+
+/// Returns `true` if the 'Connection' header value holds the 'close' option
+fn is_close(value: &[u8]) -> bool {
+    std::str::from_utf8(value).is_ok_and(|v| {
+        v.split(',')
+            .any(|opt| opt.trim().eq_ignore_ascii_case("close"))
+    })
+}
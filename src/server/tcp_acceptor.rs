@@ -1,10 +1,17 @@
+#[cfg(feature = "tls")]
+use crate::server::TlsCfg;
 use crate::{common::CONN_ID, server::Connection, service::IcapService};
-use std::{io::Result, net::SocketAddr};
+use std::{future::Future, io::Result, net::SocketAddr, sync::Arc};
 use tokio::{
+    io::{AsyncRead, AsyncWrite},
     net::{TcpListener, ToSocketAddrs},
-    task,
+    sync::{watch, Semaphore},
+    task::JoinSet,
 };
-use tracing::{debug, instrument, trace};
+use tracing::{debug, error, info, instrument, trace, warn};
+
+/// Over-limit connections answered with 503 at the same time, further ones are closed right away
+const MAX_REJECTING: usize = 16;
 
 #[derive(Debug)]
 pub struct TcpAcceptor<S>
@@ -17,6 +24,8 @@ where
     sock: TcpListener,
     local_addr: SocketAddr,
     svc: S,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsCfg>,
 }
 
 impl<S> TcpAcceptor<S>
@@ -33,23 +42,293 @@ where
             sock,
             local_addr,
             svc,
+            #[cfg(feature = "tls")]
+            tls: None,
         })
     }
 
-    #[instrument(name = "tcp_acceptor", skip(self), fields(addr=%self.local_addr))]
-    pub async fn run(&self) -> Result<()> {
+    /// Accepts TLS-encrypted connections only (ICAPS)
+    ///
+    /// The handshake must complete within
+    /// [read_timeout](crate::server::ServerCfg::read_timeout).
+    #[cfg(feature = "tls")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "tls")))]
+    #[inline]
+    pub fn with_tls(mut self, tls: TlsCfg) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub async fn run(self) -> Result<()> {
+        self.run_until(std::future::pending()).await
+    }
+
+    /// Accepts connections until `shutdown` completes, then closes the listening socket
+    /// and waits for active connections to finish their current message,
+    /// sent with 'Connection: close'
+    ///
+    /// Idle connections are closed right away, connections still active after
+    /// [shutdown_timeout](crate::server::ServerCfg::shutdown_timeout) are closed forcibly.
+    #[instrument(name = "tcp_acceptor", skip(self, shutdown), fields(addr=%self.local_addr))]
+    pub async fn run_until<F: Future<Output = ()>>(self, shutdown: F) -> Result<()> {
         trace!("start...");
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut conns = JoinSet::new();
+        // kept out of the shutdown drain, aborted when dropped
+        let mut rejects = JoinSet::new();
+        let rejecting = Arc::new(Semaphore::new(MAX_REJECTING));
+        let limit = self
+            .svc
+            .server_cfg()
+            .max_connections
+            .map(|max| Arc::new(Semaphore::new(max)));
+        tokio::pin!(shutdown);
+
         loop {
-            let (sock, addr) = self.sock.accept().await?;
-            let conn_id = CONN_ID.next();
-            let svc = self.svc.clone();
-            debug!(addr = %addr, id=%conn_id, "accepted new connection");
-
-            task::spawn(async move {
-                let mut conn = Connection::new(conn_id, sock, svc);
-                conn.process().await;
-                trace!(id=%conn.id, "connection terminated");
-            });
+            tokio::select! {
+                res = self.sock.accept() => {
+                    let (sock, addr) = match res {
+                        Ok(res) => res,
+                        Err(e) => {
+                            // keep serving the active connections
+                            conns.detach_all();
+                            return Err(e);
+                        }
+                    };
+                    let conn_id = CONN_ID.next();
+                    let svc = self.svc.clone();
+                    let shutdown_rx = shutdown_rx.clone();
+                    debug!(addr = %addr, id=%conn_id, "accepted new connection");
+                    if sock.set_nodelay(true).is_err() {
+                        error!(id=%conn_id, "failed to set TCP_NODELAY");
+                    }
+
+                    let permit = limit.as_ref().map(|l| l.clone().try_acquire_owned());
+                    let overloaded = matches!(permit, Some(Err(_)));
+                    let (permit, set) = if overloaded {
+                        warn!(addr = %addr, id=%conn_id, "too many connections");
+                        match rejecting.clone().try_acquire_owned() {
+                            Ok(permit) => (Some(permit), &mut rejects),
+                            Err(_) => {
+                                debug!(id=%conn_id, "too many rejected connections, closing");
+                                continue;
+                            }
+                        }
+                    } else {
+                        (permit.and_then(|p| p.ok()), &mut conns)
+                    };
+                    #[cfg(feature = "tls")]
+                    let tls = self.tls.as_ref().map(TlsCfg::acceptor);
+
+                    set.spawn(async move {
+                        let _permit = permit;
+                        #[cfg(feature = "tls")]
+                        if let Some(tls) = tls {
+                            let timeout = svc.server_cfg().read_timeout;
+                            match tokio::time::timeout(timeout, tls.accept(sock)).await {
+                                Ok(Ok(sock)) => {
+                                    let conn = Connection::new(conn_id, sock, svc);
+                                    serve(conn, shutdown_rx, overloaded).await;
+                                }
+                                Ok(Err(e)) => info!(id=%conn_id, err=%e, "TLS handshake failed"),
+                                Err(_) => info!(id=%conn_id, "TLS handshake timed-out"),
+                            }
+                            return;
+                        }
+                        serve(Connection::new(conn_id, sock, svc), shutdown_rx, overloaded).await;
+                    });
+                }
+                Some(_) = conns.join_next(), if !conns.is_empty() => {}
+                Some(_) = rejects.join_next(), if !rejects.is_empty() => {}
+                _ = &mut shutdown => break,
+            }
+        }
+
+        // new connections are refused while the active ones are drained
+        drop(self.sock);
+        drop(rejects);
+        info!(
+            n = conns.len(),
+            "shutting down, draining active connections"
+        );
+        shutdown_tx.send_replace(true);
+        let timeout = self.svc.server_cfg().shutdown_timeout;
+        let drain = async { while conns.join_next().await.is_some() {} };
+        if tokio::time::timeout(timeout, drain).await.is_err() {
+            warn!(
+                n = conns.len(),
+                "shutdown timeout, closing active connections"
+            );
+            conns.shutdown().await;
         }
+        info!("shut down");
+        Ok(())
+    }
+}
+
+/// Processes an accepted connection, or rejects it when the server is overloaded
+async fn serve<S, T>(mut conn: Connection<S, T>, shutdown: watch::Receiver<bool>, overloaded: bool)
+where
+    S: IcapService,
+    <S as IcapService>::OPF: Send,
+    <S as IcapService>::RQF: Send,
+    <S as IcapService>::RSF: Send,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    if overloaded {
+        conn.reject_overloaded().await;
+    } else {
+        conn = conn.with_shutdown(shutdown);
+        conn.process().await;
+    }
+    trace!(id=%conn.id, "connection terminated");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        server::{AdaptationDecision::NoAdaptation, ReqCtx, ServerCfg},
+        service::ServiceResult,
+        service_fn,
+    };
+    use std::{sync::Arc, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::oneshot,
+        task::JoinHandle,
+        time::{sleep, timeout},
+    };
+
+    const HTTP_HDR: &str = "POST / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+
+    async fn handle_options(ctx: Box<ReqCtx>) -> ServiceResult {
+        Ok(ctx)
+    }
+
+    async fn handle_mod(mut ctx: Box<ReqCtx>) -> ServiceResult {
+        ctx.set_decision(NoAdaptation);
+        Ok(ctx)
+    }
+
+    async fn start(
+        cfg: Arc<ServerCfg>,
+    ) -> (SocketAddr, oneshot::Sender<()>, JoinHandle<Result<()>>) {
+        let svc = service_fn(cfg, handle_options, handle_mod, handle_mod);
+        let acceptor = TcpAcceptor::bind(svc, "127.0.0.1:0").await.unwrap();
+        let addr = acceptor.local_addr();
+        let (tx, rx) = oneshot::channel::<()>();
+        let srv = tokio::spawn(async move {
+            acceptor
+                .run_until(async {
+                    rx.await.ok();
+                })
+                .await
+        });
+        (addr, tx, srv)
+    }
+
+    async fn send_head(addr: SocketAddr) -> TcpStream {
+        let mut cli = TcpStream::connect(addr).await.unwrap();
+        let head = format!(
+            "REQMOD icap://127.0.0.1/svc ICAP/1.0\r\n\
+            Host: 127.0.0.1\r\n\
+            Allow: 204\r\n\
+            Encapsulated: req-hdr=0, req-body={}\r\n\
+            \r\n\
+            {}5\r\nhel",
+            HTTP_HDR.len(),
+            HTTP_HDR
+        );
+        cli.write_all(head.as_bytes()).await.unwrap();
+        cli
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_connections() {
+        let (addr, shutdown, srv) = start(ServerCfg::builder().build()).await;
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        let mut active = send_head(addr).await;
+        sleep(Duration::from_millis(50)).await;
+
+        shutdown.send(()).unwrap();
+        sleep(Duration::from_millis(50)).await;
+        assert!(TcpStream::connect(addr).await.is_err());
+        active.write_all(b"lo\r\n0\r\n\r\n").await.unwrap();
+
+        let mut res = Vec::new();
+        active.read_to_end(&mut res).await.unwrap();
+        let res = String::from_utf8(res).unwrap();
+        assert!(res.starts_with("ICAP/1.0 204 No Content\r\n"));
+        assert!(res.contains("\r\nconnection: close\r\n"));
+
+        let mut res = Vec::new();
+        assert_eq!(idle.read_to_end(&mut res).await.unwrap(), 0);
+
+        let res = timeout(Duration::from_secs(5), srv).await.unwrap();
+        assert!(res.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_timeout() {
+        let cfg = ServerCfg::builder()
+            .shutdown_timeout(Duration::from_millis(100))
+            .build();
+        let (addr, shutdown, srv) = start(cfg).await;
+        let mut stuck = send_head(addr).await;
+        sleep(Duration::from_millis(50)).await;
+
+        shutdown.send(()).unwrap();
+        let res = timeout(Duration::from_secs(5), srv).await.unwrap();
+        assert!(res.unwrap().is_ok());
+
+        let mut res = Vec::new();
+        let read = timeout(Duration::from_secs(5), stuck.read_to_end(&mut res)).await;
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let (addr, shutdown, srv) = start(ServerCfg::builder().max_connections(1).build()).await;
+        let active = TcpStream::connect(addr).await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+
+        let mut rejected = send_head(addr).await;
+        let mut res = Vec::new();
+        timeout(Duration::from_secs(5), rejected.read_to_end(&mut res))
+            .await
+            .unwrap()
+            .unwrap();
+        let res = String::from_utf8(res).unwrap();
+        assert!(
+            res.starts_with("ICAP/1.0 503 Service Overloaded\r\n"),
+            "{}",
+            res
+        );
+        assert!(res.contains("\r\nConnection: close\r\n"));
+        assert!(res.contains("\r\nEncapsulated: null-body=0\r\n"));
+
+        drop(active);
+        sleep(Duration::from_millis(50)).await;
+        let mut accepted = send_head(addr).await;
+        accepted.write_all(b"lo\r\n0\r\n\r\n").await.unwrap();
+        let mut buf = [0u8; 64];
+        let n = timeout(Duration::from_secs(5), accepted.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(buf[..n].starts_with(b"ICAP/1.0 204 No Content\r\n"));
+
+        // the rejected connection, still open, does not delay the shutdown
+        shutdown.send(()).unwrap();
+        let res = timeout(Duration::from_secs(2), srv).await.unwrap();
+        assert!(res.unwrap().is_ok());
+        drop(rejected);
     }
 }
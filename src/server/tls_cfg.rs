@@ -0,0 +1,323 @@
+use crate::errors::TlsCfgError;
+use rustls_pemfile::Item;
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio_rustls::{
+    rustls::{
+        server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+/// TLS settings of an ICAPS listener, see [TcpAcceptor::with_tls](crate::server::TcpAcceptor::with_tls)
+#[derive(Debug, Clone)]
+pub struct TlsCfg {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsCfg {
+    #[inline]
+    pub fn builder() -> TlsCfgBuilder {
+        TlsCfgBuilder::default()
+    }
+
+    #[inline]
+    pub(crate) fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.config.clone())
+    }
+}
+
+/// Uses a custom rustls configuration
+impl From<Arc<ServerConfig>> for TlsCfg {
+    fn from(config: Arc<ServerConfig>) -> Self {
+        TlsCfg { config }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct TlsCfgBuilder {
+    cert_file: Option<PathBuf>,
+    key_file: Option<PathBuf>,
+    client_ca_file: Option<PathBuf>,
+}
+
+impl TlsCfgBuilder {
+    /// Sets the PEM file with the server certificate chain, the leaf certificate first
+    #[inline]
+    pub fn cert_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.cert_file = Some(path.into());
+        self
+    }
+
+    /// Sets the PEM file with the private key of the server certificate,
+    /// PKCS#8, PKCS#1 (RSA) or SEC1 (EC)
+    #[inline]
+    pub fn key_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.key_file = Some(path.into());
+        self
+    }
+
+    /// Sets the PEM file with CA certificates, clients are required to present
+    /// a certificate signed by one of them
+    #[inline]
+    pub fn client_ca_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.client_ca_file = Some(path.into());
+        self
+    }
+
+    pub fn build(self) -> Result<TlsCfg, TlsCfgError> {
+        let cert_file = self
+            .cert_file
+            .ok_or(TlsCfgError::Missing("certificate file"))?;
+        let key_file = self
+            .key_file
+            .ok_or(TlsCfgError::Missing("private key file"))?;
+        let certs = load_certs(&cert_file)?;
+        let key = load_key(&key_file)?;
+
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let config = match self.client_ca_file {
+            Some(ca_file) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(&ca_file)? {
+                    roots
+                        .add(&cert)
+                        .map_err(|e| TlsCfgError::BadClientCa(e.to_string()))?;
+                }
+                builder
+                    .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+                    .with_single_cert(certs, key)?
+            }
+            None => builder.with_no_client_auth().with_single_cert(certs, key)?,
+        };
+        Ok(TlsCfg {
+            config: Arc::new(config),
+        })
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>, TlsCfgError> {
+    let io_err = |source| TlsCfgError::Io {
+        path: path.to_owned(),
+        source,
+    };
+    let file = File::open(path).map_err(io_err)?;
+    rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(io_err)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, TlsCfgError> {
+    let certs: Vec<_> = read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        return Err(TlsCfgError::NoCertificates(path.to_owned()));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, TlsCfgError> {
+    read_pem(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| TlsCfgError::NoPrivateKey(path.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        server::{ReqCtx, ServerCfg, TcpAcceptor},
+        service::ServiceResult,
+        service_fn,
+    };
+    use rcgen::{BasicConstraints, Certificate as RcCert, CertificateParams, IsCa};
+    use std::{fs, net::SocketAddr};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::oneshot,
+    };
+    use tokio_rustls::{
+        rustls::{ClientConfig, ServerName},
+        TlsConnector,
+    };
+
+    const OPTIONS_REQ: &[u8] = b"OPTIONS icap://localhost/svc ICAP/1.0\r\n\
+        Host: localhost\r\n\
+        Connection: close\r\n\
+        \r\n";
+
+    async fn handle(ctx: Box<ReqCtx>) -> ServiceResult {
+        Ok(ctx)
+    }
+
+    struct Pki {
+        dir: PathBuf,
+        server: RcCert,
+    }
+
+    impl Pki {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("icap-tls-{}-{}", std::process::id(), name));
+            fs::create_dir_all(&dir).unwrap();
+            let server = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+            let pki = Pki { dir, server };
+            pki.write("server.pem", &pki.server.serialize_pem().unwrap());
+            pki.write("server.key", &pki.server.serialize_private_key_pem());
+            pki
+        }
+
+        fn write(&self, name: &str, pem: &str) -> PathBuf {
+            let path = self.dir.join(name);
+            fs::write(&path, pem).unwrap();
+            path
+        }
+
+        fn server_cfg(&self) -> TlsCfgBuilder {
+            TlsCfg::builder()
+                .cert_file(self.dir.join("server.pem"))
+                .key_file(self.dir.join("server.key"))
+        }
+
+        fn client_cfg(&self, auth: Option<(Vec<Certificate>, PrivateKey)>) -> ClientConfig {
+            let mut roots = RootCertStore::empty();
+            roots
+                .add(&Certificate(self.server.serialize_der().unwrap()))
+                .unwrap();
+            let builder = ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots);
+            match auth {
+                Some((certs, key)) => builder.with_client_auth_cert(certs, key).unwrap(),
+                None => builder.with_no_client_auth(),
+            }
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.dir).ok();
+        }
+    }
+
+    async fn start(tls: TlsCfg) -> (SocketAddr, oneshot::Sender<()>) {
+        let svc = service_fn(ServerCfg::builder().build(), handle, handle, handle);
+        let acceptor = TcpAcceptor::bind(svc, "127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_tls(tls);
+        let addr = acceptor.local_addr();
+        let (tx, rx) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            acceptor
+                .run_until(async {
+                    rx.await.ok();
+                })
+                .await
+        });
+        (addr, tx)
+    }
+
+    async fn roundtrip(addr: SocketAddr, cfg: ClientConfig) -> std::io::Result<String> {
+        let sock = TcpStream::connect(addr).await?;
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut cli = TlsConnector::from(Arc::new(cfg))
+            .connect(name, sock)
+            .await?;
+        cli.write_all(OPTIONS_REQ).await?;
+        let mut res = Vec::new();
+        cli.read_to_end(&mut res).await?;
+        Ok(String::from_utf8(res).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_tls() {
+        let pki = Pki::new("tls");
+        let (addr, _shutdown) = start(pki.server_cfg().build().unwrap()).await;
+
+        let cfg = pki.client_cfg(None);
+        let res = roundtrip(addr, cfg).await.unwrap();
+        assert!(res.starts_with("ICAP/1.0 200 OK\r\n"), "{}", res);
+
+        // a plain-text client gets no ICAP response
+        let mut cli = TcpStream::connect(addr).await.unwrap();
+        cli.write_all(OPTIONS_REQ).await.unwrap();
+        let mut res = Vec::new();
+        cli.read_to_end(&mut res).await.ok();
+        assert!(!res.starts_with(b"ICAP/1.0"));
+    }
+
+    #[tokio::test]
+    async fn test_client_cert() {
+        let pki = Pki::new("client-cert");
+        let mut ca_params = CertificateParams::new(vec![]);
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = RcCert::from_params(ca_params).unwrap();
+        let ca_file = pki.write("ca.pem", &ca.serialize_pem().unwrap());
+        let client = rcgen::generate_simple_self_signed(vec!["client".into()]).unwrap();
+
+        let tls = pki.server_cfg().client_ca_file(ca_file).build().unwrap();
+        let (addr, _shutdown) = start(tls).await;
+
+        let cfg = pki.client_cfg(None);
+        assert!(roundtrip(addr, cfg).await.is_err());
+
+        let certs = vec![Certificate(pem_der(
+            &client.serialize_pem_with_signer(&ca).unwrap(),
+        ))];
+        let key = PrivateKey(client.serialize_private_key_der());
+        let cfg = pki.client_cfg(Some((certs, key)));
+        let res = roundtrip(addr, cfg).await.unwrap();
+        assert!(res.starts_with("ICAP/1.0 200 OK\r\n"), "{}", res);
+    }
+
+    fn pem_der(pem: &str) -> Vec<u8> {
+        match rustls_pemfile::read_one(&mut pem.as_bytes()).unwrap() {
+            Some(Item::X509Certificate(der)) => der,
+            _ => panic!("no certificate"),
+        }
+    }
+
+    #[test]
+    fn test_build_errors() {
+        let pki = Pki::new("errors");
+        let cert = pki.dir.join("server.pem");
+        let key = pki.dir.join("server.key");
+
+        let err = TlsCfg::builder().key_file(&key).build().unwrap_err();
+        assert!(matches!(err, TlsCfgError::Missing("certificate file")));
+
+        let err = TlsCfg::builder()
+            .cert_file(pki.dir.join("none.pem"))
+            .key_file(&key)
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, TlsCfgError::Io { .. }), "{}", err);
+
+        let err = TlsCfg::builder()
+            .cert_file(&key)
+            .key_file(&key)
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, TlsCfgError::NoCertificates(_)), "{}", err);
+
+        let err = TlsCfg::builder()
+            .cert_file(&cert)
+            .key_file(&cert)
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, TlsCfgError::NoPrivateKey(_)), "{}", err);
+    }
+}
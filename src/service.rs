@@ -4,6 +4,9 @@ use std::{boxed::Box, future::Future, sync::Arc};
 mod error_code;
 pub use error_code::*;
 
+mod router;
+pub use router::*;
+
 pub type ServiceResult = Result<Box<ReqCtx>, ErrorCode>;
 
 pub trait IcapService: Clone {
@@ -13,6 +16,18 @@ pub trait IcapService: Clone {
 
     fn server_cfg(&self) -> Arc<ServerCfg>;
 
+    /// Returns the configuration of the service handling requests to `uri`
+    #[inline]
+    fn service_cfg(&self, _uri: &http::Uri) -> Arc<ServerCfg> {
+        self.server_cfg()
+    }
+
+    /// Returns `true` if requests to `uri` are handled, otherwise the server answers 404
+    #[inline]
+    fn has_service(&self, _uri: &http::Uri) -> bool {
+        true
+    }
+
     fn handle_options(&mut self, ctx: Box<ReqCtx>) -> Self::OPF;
 
     fn handle_reqmod(&mut self, ctx: Box<ReqCtx>) -> Self::RQF;
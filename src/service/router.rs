@@ -0,0 +1,156 @@
+use crate::{
+    server::{ReqCtx, ServerCfg},
+    service::{ErrorCode, IcapService, ServiceResult},
+};
+use std::{boxed::Box, collections::HashMap, fmt, future::Future, pin::Pin, sync::Arc};
+
+const NOT_FOUND: ErrorCode = ErrorCode(404);
+
+pub type BoxServiceFuture = Pin<Box<dyn Future<Output = ServiceResult> + Send>>;
+
+trait DynService: Send {
+    fn server_cfg(&self) -> Arc<ServerCfg>;
+
+    fn handle_options(&mut self, ctx: Box<ReqCtx>) -> BoxServiceFuture;
+
+    fn handle_reqmod(&mut self, ctx: Box<ReqCtx>) -> BoxServiceFuture;
+
+    fn handle_respmod(&mut self, ctx: Box<ReqCtx>) -> BoxServiceFuture;
+
+    fn clone_box(&self) -> Box<dyn DynService>;
+}
+
+impl<S> DynService for S
+where
+    S: IcapService + Send + 'static,
+    <S as IcapService>::OPF: Send + 'static,
+    <S as IcapService>::RQF: Send + 'static,
+    <S as IcapService>::RSF: Send + 'static,
+{
+    #[inline]
+    fn server_cfg(&self) -> Arc<ServerCfg> {
+        IcapService::server_cfg(self)
+    }
+
+    #[inline]
+    fn handle_options(&mut self, ctx: Box<ReqCtx>) -> BoxServiceFuture {
+        Box::pin(IcapService::handle_options(self, ctx))
+    }
+
+    #[inline]
+    fn handle_reqmod(&mut self, ctx: Box<ReqCtx>) -> BoxServiceFuture {
+        Box::pin(IcapService::handle_reqmod(self, ctx))
+    }
+
+    #[inline]
+    fn handle_respmod(&mut self, ctx: Box<ReqCtx>) -> BoxServiceFuture {
+        Box::pin(IcapService::handle_respmod(self, ctx))
+    }
+
+    #[inline]
+    fn clone_box(&self) -> Box<dyn DynService> {
+        Box::new(self.clone())
+    }
+}
+
+/// Dispatches requests to services by the path of the ICAP request URI
+///
+/// Requests to unknown paths are answered with 404 Service not found.
+pub struct Router {
+    cfg: Arc<ServerCfg>,
+    routes: HashMap<String, Box<dyn DynService>>,
+}
+
+impl Router {
+    #[inline]
+    pub fn new(cfg: Arc<ServerCfg>) -> Self {
+        Self {
+            cfg,
+            routes: HashMap::new(),
+        }
+    }
+
+    /// Adds `svc` for requests to `path`, e.g. "/av"
+    pub fn route<S>(mut self, path: impl Into<String>, svc: S) -> Self
+    where
+        S: IcapService + Send + 'static,
+        <S as IcapService>::OPF: Send + 'static,
+        <S as IcapService>::RQF: Send + 'static,
+        <S as IcapService>::RSF: Send + 'static,
+    {
+        self.routes.insert(path.into(), Box::new(svc));
+        self
+    }
+
+    fn service(&mut self, ctx: &ReqCtx) -> Option<&mut Box<dyn DynService>> {
+        self.routes.get_mut(ctx.icap_req().uri.path())
+    }
+}
+
+impl IcapService for Router {
+    type OPF = BoxServiceFuture;
+    type RQF = BoxServiceFuture;
+    type RSF = BoxServiceFuture;
+
+    #[inline]
+    fn server_cfg(&self) -> Arc<ServerCfg> {
+        self.cfg.clone()
+    }
+
+    /// Returns the configuration of the routed service, or of the router for unknown paths
+    #[inline]
+    fn service_cfg(&self, uri: &http::Uri) -> Arc<ServerCfg> {
+        match self.routes.get(uri.path()) {
+            Some(svc) => svc.server_cfg(),
+            None => self.cfg.clone(),
+        }
+    }
+
+    #[inline]
+    fn has_service(&self, uri: &http::Uri) -> bool {
+        self.routes.contains_key(uri.path())
+    }
+
+    fn handle_options(&mut self, ctx: Box<ReqCtx>) -> Self::OPF {
+        match self.service(&ctx) {
+            Some(svc) => svc.handle_options(ctx),
+            None => Box::pin(async { Err(NOT_FOUND) }),
+        }
+    }
+
+    fn handle_reqmod(&mut self, ctx: Box<ReqCtx>) -> Self::RQF {
+        match self.service(&ctx) {
+            Some(svc) => svc.handle_reqmod(ctx),
+            None => Box::pin(async { Err(NOT_FOUND) }),
+        }
+    }
+
+    fn handle_respmod(&mut self, ctx: Box<ReqCtx>) -> Self::RSF {
+        match self.service(&ctx) {
+            Some(svc) => svc.handle_respmod(ctx),
+            None => Box::pin(async { Err(NOT_FOUND) }),
+        }
+    }
+}
+
+impl Clone for Router {
+    fn clone(&self) -> Self {
+        Self {
+            cfg: self.cfg.clone(),
+            routes: self
+                .routes
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone_box()))
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Router")
+            .field("cfg", &self.cfg)
+            .field("routes", &self.routes.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}